@@ -0,0 +1,178 @@
+use axum::extract::{Path, State};
+use http::{HeaderMap, StatusCode, header::AUTHORIZATION};
+use std::{str::FromStr, sync::Arc};
+use subtle::ConstantTimeEq;
+use tower_sessions::{
+    Session, SessionStore,
+    cookie::{Cookie, CookieJar, Key},
+    session::Id,
+};
+
+use crate::{
+    components::poker::room::backend::ServerState, session_store::AnySessionStore,
+    uid::get_identity,
+};
+
+/// Token the `/admin` endpoints are protected by, they are disabled without one
+#[derive(Debug, Clone, Default)]
+pub struct AdminToken(Option<Arc<str>>);
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> Self {
+        Self(token.map(Into::into))
+    }
+
+    fn check(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let Some(token) = &self.0 else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        let provided = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match provided {
+            // Compared in constant time, so the token can't be guessed byte
+            // by byte from response times
+            Some(provided) if bool::from(provided.as_bytes().ct_eq(token.as_bytes())) => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+/// Name and signing key of the session cookie, to tell the session id from
+/// the cookie's value
+#[derive(Debug, Clone)]
+pub struct SessionCookie {
+    name: Arc<str>,
+    key: Option<Key>,
+}
+
+impl SessionCookie {
+    pub fn new(name: String, key: Option<Key>) -> Self {
+        Self {
+            name: name.into(),
+            key,
+        }
+    }
+
+    /// Takes the cookie's value, signed or not, as well as the bare id
+    fn session_id(&self, value: &str) -> Option<Id> {
+        let verified = self.key.as_ref().and_then(|key| {
+            let mut jar = CookieJar::new();
+            jar.add_original(Cookie::new(self.name.to_string(), value.to_owned()));
+            jar.signed(key).get(&self.name)
+        });
+        match verified {
+            Some(cookie) => Id::from_str(cookie.value()).ok(),
+            None => Id::from_str(value).ok(),
+        }
+    }
+}
+
+/// Drops the session by the value of its cookie (signed or not, url-encoded)
+/// or its bare id, e.g. one left behind in a shared browser. Its player is
+/// removed from the open rooms too.
+pub async fn revoke_session(
+    State(token): State<AdminToken>,
+    State(store): State<AnySessionStore>,
+    State(server_state): State<ServerState>,
+    State(cookie): State<SessionCookie>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    token.check(&headers)?;
+    let id = cookie
+        .session_id(&session_id)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    match store.load(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to load session to revoke: {e}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    let session = Session::new(Some(id), Arc::new(store.clone()), None);
+    let identity = get_identity(&session).await.map_err(|e| {
+        tracing::error!("Failed to load identity of the session to revoke: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    store.delete(&id).await.map_err(|e| {
+        tracing::error!("Failed to revoke session: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if let Some(identity) = identity {
+        server_state.forget_player(identity.uid).await;
+    }
+    tracing::info!("Revoked session {id}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{session_store::MemorySessionStore, uid::get_or_create_identity};
+    use http::HeaderValue;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn signed_value(key: &Key, name: &str, id: Id) -> String {
+        let mut jar = CookieJar::new();
+        jar.signed_mut(key).add(Cookie::new(name.to_owned(), id.to_string()));
+        jar.get(name).unwrap().value().to_owned()
+    }
+
+    #[test]
+    fn session_id_from_cookie_value() {
+        let key = Key::generate();
+        let id = Id::default();
+        let signed = signed_value(&key, "id", id);
+        let cookie = SessionCookie::new("id".to_owned(), Some(key));
+        assert_eq!(cookie.session_id(&signed), Some(id));
+        assert_eq!(cookie.session_id(&id.to_string()), Some(id));
+        assert_eq!(cookie.session_id(&signed_value(&Key::generate(), "id", id)), None);
+
+        let unsigned = SessionCookie::new("id".to_owned(), None);
+        assert_eq!(unsigned.session_id(&id.to_string()), Some(id));
+        assert_eq!(unsigned.session_id("garbage"), None);
+    }
+
+    #[tokio::test]
+    async fn revoked_sessions_are_gone() {
+        let store = AnySessionStore::Memory(MemorySessionStore::default());
+        let session = Session::new(None, Arc::new(store.clone()), None);
+        get_or_create_identity(&session, &store).await.unwrap();
+        session.save().await.unwrap();
+        let id = session.id().unwrap();
+
+        let key = Key::generate();
+        let revoke = |headers: HeaderMap, value: String| {
+            revoke_session(
+                State(AdminToken::new(Some(TOKEN.to_owned()))),
+                State(store.clone()),
+                State(ServerState::default()),
+                State(SessionCookie::new("id".to_owned(), Some(key.clone()))),
+                headers,
+                Path(value),
+            )
+        };
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            revoke(headers.clone(), id.to_string()).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {TOKEN}")).unwrap(),
+        );
+        assert_eq!(
+            revoke(headers.clone(), signed_value(&key, "id", id)).await,
+            Ok(StatusCode::NO_CONTENT)
+        );
+        assert_eq!(store.load(&id).await.unwrap(), None);
+        assert_eq!(
+            revoke(headers, id.to_string()).await,
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+}
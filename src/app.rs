@@ -1,7 +1,10 @@
-use crate::components::poker::{main::frontend::PickRoom, room::frontend::PokerRoom};
+use crate::components::{
+    poker::{main::frontend::PickRoom, room::frontend::PokerRoom},
+    toast::{Toaster, provide_toasts},
+};
 use crate::error_template::{AppError, ErrorTemplate};
 use leptos::prelude::*;
-use leptos_meta::{provide_meta_context, MetaTags};
+use leptos_meta::{MetaTags, provide_meta_context};
 use leptos_router::{
     components::{Route, Router, Routes},
     path,
@@ -32,6 +35,7 @@ pub fn shell(options: LeptosOptions) -> impl IntoView {
 #[component]
 pub fn App() -> impl IntoView {
     provide_meta_context();
+    provide_toasts();
 
     view! {
         // id=leptos means cargo-leptos will hot-reload this stylesheet
@@ -48,6 +52,7 @@ pub fn App() -> impl IntoView {
                     <Route path=path!("rooms/:room_id") view=PokerRoom/>
                 </Routes>
             </main>
+            <Toaster />
         </Router>
     }
 }
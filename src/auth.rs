@@ -0,0 +1,524 @@
+use axum::extract::{Query, State};
+use axum::response::Redirect;
+use http::StatusCode;
+use openidconnect::{
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet, EndpointNotSet,
+    EndpointSet, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+    TokenResponse,
+    core::{CoreAuthenticationFlow, CoreClient, CoreIdTokenClaims, CoreProviderMetadata},
+    reqwest,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc};
+use tower_sessions::Session;
+
+use crate::{
+    components::poker::room::{api::check_username, backend::ServerState},
+    config::OidcConfig,
+    uid::{Account, Identity, get_identity, replace_identity, reset_uid},
+};
+
+/// Where the login in progress is remembered between `/auth/login` and
+/// `/auth/callback`
+const PENDING_LOGIN_KEY: &str = "oidc_login";
+const MAX_NAME_LEN: usize = 32;
+
+pub type DiscoveryError = openidconnect::DiscoveryError<openidconnect::HttpClientError<reqwest::Error>>;
+
+type Client = CoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
+
+type AuthError = (StatusCode, &'static str);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    csrf_token: String,
+    nonce: String,
+    pkce_verifier: String,
+    return_to: String,
+}
+
+struct Provider {
+    client: Client,
+    http: reqwest::Client,
+    scopes: Vec<Scope>,
+}
+
+/// Single sign-on with an OpenID Connect provider, disabled unless one is
+/// configured
+#[derive(Clone, Default)]
+pub struct OidcLogin(Option<Arc<Provider>>);
+
+impl fmt::Debug for OidcLogin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OidcLogin")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+impl OidcLogin {
+    /// Fetches the provider's endpoints and keys, the config is expected to
+    /// be validated
+    pub async fn discover(config: &OidcConfig) -> Result<Self, DiscoveryError> {
+        let Some(issuer_url) = &config.issuer_url else {
+            return Ok(Self::default());
+        };
+        let http = reqwest::ClientBuilder::new()
+            // Following redirects would let the provider point us anywhere
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("HTTP client to build");
+        let issuer_url = IssuerUrl::new(issuer_url.clone()).expect("Config to be validated");
+        let metadata = CoreProviderMetadata::discover_async(issuer_url, &http).await?;
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            ClientId::new(config.client_id.clone()),
+            config.client_secret.clone().map(ClientSecret::new),
+        )
+        .set_redirect_uri(
+            RedirectUrl::new(config.redirect_url.clone()).expect("Config to be validated"),
+        );
+        Ok(Self(Some(Arc::new(Provider {
+            client,
+            http,
+            scopes: config.scopes.iter().cloned().map(Scope::new).collect(),
+        }))))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    fn provider(&self) -> Result<&Provider, AuthError> {
+        self.0
+            .as_deref()
+            .ok_or((StatusCode::NOT_FOUND, "Login is disabled"))
+    }
+}
+
+fn internal_error(e: impl fmt::Display) -> AuthError {
+    tracing::error!("Login failed: {e}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Login failed")
+}
+
+fn provider_error(e: impl fmt::Display) -> AuthError {
+    tracing::warn!("Login rejected: {e}");
+    (StatusCode::BAD_GATEWAY, "The identity provider rejected the login")
+}
+
+/// Only paths of this site, so the login can't be used to send players
+/// elsewhere
+fn local_path(path: Option<String>) -> String {
+    path.filter(|path| {
+        path.starts_with('/') && !path.starts_with("//") && !path.starts_with("/\\")
+    })
+    .unwrap_or_else(|| "/".to_owned())
+}
+
+/// The name the directory knows the player by, made fit for a player name
+fn directory_name(claims: &CoreIdTokenClaims) -> Option<String> {
+    let candidates = [
+        claims
+            .name()
+            .and_then(|name| name.get(None))
+            .map(|name| name.to_string()),
+        claims
+            .preferred_username()
+            .map(|name| name.to_string()),
+        claims
+            .email()
+            .and_then(|email| email.split_once('@').map(|(local, _)| local.to_owned())),
+    ];
+    candidates.into_iter().flatten().find_map(|name| {
+        let name: String = name
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join("_")
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+            .take(MAX_NAME_LEN)
+            .collect();
+        check_username(&name).is_ok().then_some(name)
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    return_to: Option<String>,
+}
+
+/// Sends the player to the provider to sign in
+pub async fn login(
+    State(oidc): State<OidcLogin>,
+    session: Session,
+    Query(params): Query<LoginParams>,
+) -> Result<Redirect, AuthError> {
+    let provider = oidc.provider()?;
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (url, csrf_token, nonce) = provider
+        .client
+        .authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .add_scopes(provider.scopes.iter().cloned())
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+    let pending = PendingLogin {
+        csrf_token: csrf_token.into_secret(),
+        nonce: nonce.secret().clone(),
+        pkce_verifier: pkce_verifier.into_secret(),
+        return_to: local_path(params.return_to),
+    };
+    session
+        .insert(PENDING_LOGIN_KEY, pending)
+        .await
+        .map_err(internal_error)?;
+    Ok(Redirect::to(url.as_str()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Where the provider sends the player back to. The session gets the identity
+/// of the account, the uid the player had before is removed from the rooms
+/// unless it belonged to another account.
+pub async fn callback(
+    State(oidc): State<OidcLogin>,
+    State(state): State<ServerState>,
+    session: Session,
+    Query(params): Query<CallbackParams>,
+) -> Result<Redirect, AuthError> {
+    let provider = oidc.provider()?;
+    let pending: PendingLogin = session
+        .remove(PENDING_LOGIN_KEY)
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::BAD_REQUEST, "No login in progress"))?;
+    if params.state.as_deref() != Some(pending.csrf_token.as_str()) {
+        return Err((StatusCode::BAD_REQUEST, "Login state mismatch"));
+    }
+    if let Some(error) = params.error {
+        // E.g. the player declined, they stay who they were
+        tracing::info!("Login aborted by the provider: {error}");
+        return Ok(Redirect::to(&pending.return_to));
+    }
+    let code = params
+        .code
+        .ok_or((StatusCode::BAD_REQUEST, "No authorization code"))?;
+
+    let tokens = provider
+        .client
+        .exchange_code(AuthorizationCode::new(code))
+        .map_err(internal_error)?
+        .set_pkce_verifier(PkceCodeVerifier::new(pending.pkce_verifier))
+        .request_async(&provider.http)
+        .await
+        .map_err(provider_error)?;
+    let id_token = tokens
+        .id_token()
+        .ok_or_else(|| provider_error("No id token in the response"))?;
+    let claims = id_token
+        .claims(
+            &provider.client.id_token_verifier(),
+            &Nonce::new(pending.nonce),
+        )
+        .map_err(provider_error)?;
+
+    let account = Account {
+        issuer: claims.issuer().to_string(),
+        subject: claims.subject().to_string(),
+    };
+    let identity = Identity::for_account(account, directory_name(claims));
+    let previous = get_identity(&session).await.map_err(internal_error)?;
+    replace_identity(&session, &identity)
+        .await
+        .map_err(internal_error)?;
+    if let Some(previous) =
+        previous.filter(|previous| previous.account.is_none() && previous.uid != identity.uid)
+    {
+        state.forget_player(previous.uid).await;
+    }
+    tracing::info!("Player {} signed in", identity.uid);
+    Ok(Redirect::to(&pending.return_to))
+}
+
+/// Continues anonymously, the account keeps its rooms
+pub async fn logout(State(oidc): State<OidcLogin>, session: Session) -> Result<Redirect, AuthError> {
+    oidc.provider()?;
+    let uid = reset_uid(&session).await.map_err(internal_error)?;
+    tracing::info!("Signed out, continuing as {uid}");
+    Ok(Redirect::to("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_store::{AnySessionStore, MemorySessionStore};
+    use axum::{Form, Json, Router, response::IntoResponse, routing::get, routing::post};
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use openidconnect::{
+        PrivateSigningKey,
+        core::{CoreHmacKey, CoreJwsSigningAlgorithm},
+        url::Url,
+    };
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use std::{collections::HashMap, sync::Mutex};
+    use time::OffsetDateTime;
+
+    const CLIENT_ID: &str = "scrum-poker";
+    const CLIENT_SECRET: &str = "a secret shared with the mock provider";
+
+    /// What the provider learned from the authorization request, the tests
+    /// fill it in from the url the player is sent to
+    #[derive(Default)]
+    struct Authorization {
+        code_challenge: String,
+        nonce: String,
+    }
+
+    type MockState = Arc<Mutex<Authorization>>;
+
+    /// Serves discovery and a token endpoint checking PKCE and signing id
+    /// tokens with the client secret
+    async fn mock_provider() -> (String, MockState) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let authorization = MockState::default();
+        let metadata = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "jwks_uri": format!("{issuer}/jwks"),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["HS256"],
+        });
+        let token_issuer = issuer.clone();
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(move || async move { Json(metadata) }),
+            )
+            .route("/jwks", get(|| async { Json(json!({ "keys": [] })) }))
+            .route(
+                "/token",
+                post(
+                    move |State(authorization): State<MockState>,
+                          Form(form): Form<HashMap<String, String>>| async move {
+                        let authorization = authorization.lock().unwrap();
+                        let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+                        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier));
+                        if challenge != authorization.code_challenge {
+                            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" })))
+                                .into_response();
+                        }
+                        let id_token = sign(json!({
+                            "iss": token_issuer,
+                            "sub": "player-1",
+                            "aud": CLIENT_ID,
+                            "iat": OffsetDateTime::now_utc().unix_timestamp(),
+                            "exp": OffsetDateTime::now_utc().unix_timestamp() + 60,
+                            "nonce": authorization.nonce,
+                            "name": "Ada Lovelace",
+                        }));
+                        Json(json!({
+                            "access_token": "access",
+                            "token_type": "Bearer",
+                            "id_token": id_token,
+                        }))
+                        .into_response()
+                    },
+                ),
+            )
+            .with_state(authorization.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (issuer, authorization)
+    }
+
+    fn sign(claims: serde_json::Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": "HS256", "typ": "JWT" }).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let message = format!("{header}.{payload}");
+        let signature = CoreHmacKey::new(CLIENT_SECRET)
+            .sign(&CoreJwsSigningAlgorithm::HmacSha256, message.as_bytes())
+            .unwrap();
+        format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    async fn oidc_login() -> (OidcLogin, MockState) {
+        let (issuer, authorization) = mock_provider().await;
+        let config = OidcConfig {
+            issuer_url: Some(issuer),
+            client_id: CLIENT_ID.to_owned(),
+            client_secret: Some(CLIENT_SECRET.to_owned()),
+            redirect_url: "http://localhost/auth/callback".to_owned(),
+            ..Default::default()
+        };
+        (OidcLogin::discover(&config).await.unwrap(), authorization)
+    }
+
+    fn new_session() -> Session {
+        let store = AnySessionStore::Memory(MemorySessionStore::default());
+        Session::new(None, Arc::new(store), None)
+    }
+
+    fn location(redirect: Redirect) -> String {
+        let response = redirect.into_response();
+        response.headers()[http::header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Starts a login and tells the provider about it, returns the state to
+    /// come back with
+    async fn start_login(oidc: &OidcLogin, authorization: &MockState, session: &Session) -> String {
+        let params = LoginParams {
+            return_to: Some("/rooms/team-1".to_owned()),
+        };
+        let redirect = login(State(oidc.clone()), session.clone(), Query(params))
+            .await
+            .unwrap();
+        let url = Url::parse(&location(redirect)).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["code_challenge_method"], "S256");
+        *authorization.lock().unwrap() = Authorization {
+            code_challenge: query["code_challenge"].clone(),
+            nonce: query["nonce"].clone(),
+        };
+        query["state"].clone()
+    }
+
+    async fn finish_login(
+        oidc: &OidcLogin,
+        session: &Session,
+        state: Option<String>,
+    ) -> Result<String, AuthError> {
+        let params = CallbackParams {
+            code: Some("code".to_owned()),
+            state,
+            error: None,
+        };
+        callback(
+            State(oidc.clone()),
+            State(ServerState::default()),
+            session.clone(),
+            Query(params),
+        )
+        .await
+        .map(location)
+    }
+
+    #[test]
+    fn only_local_paths() {
+        for (path, expected) in [
+            (None, "/"),
+            (Some("/rooms/1?x=1"), "/rooms/1?x=1"),
+            (Some("https://evil.example"), "/"),
+            (Some("//evil.example"), "/"),
+            (Some("/\\evil.example"), "/"),
+            (Some("room"), "/"),
+            (Some(""), "/"),
+        ] {
+            assert_eq!(local_path(path.map(str::to_owned)), expected, "{path:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn signs_in_with_the_account() {
+        let (oidc, authorization) = oidc_login().await;
+        let session = new_session();
+        let state = start_login(&oidc, &authorization, &session).await;
+
+        assert_eq!(finish_login(&oidc, &session, Some(state)).await.unwrap(), "/rooms/team-1");
+        let identity = get_identity(&session).await.unwrap().unwrap();
+        let account = identity.account.unwrap();
+        assert_eq!(account.subject, "player-1");
+        assert_eq!(identity.uid, account.uid());
+        assert_eq!(identity.display_name.as_deref(), Some("Ada_Lovelace"));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_state() {
+        let (oidc, authorization) = oidc_login().await;
+        let session = new_session();
+        assert_eq!(
+            finish_login(&oidc, &session, Some("state".to_owned())).await,
+            Err((StatusCode::BAD_REQUEST, "No login in progress"))
+        );
+
+        let state = start_login(&oidc, &authorization, &session).await;
+        assert_eq!(
+            finish_login(&oidc, &session, Some("forged".to_owned())).await,
+            Err((StatusCode::BAD_REQUEST, "Login state mismatch"))
+        );
+        // The login can't be retried with the right state either
+        assert_eq!(
+            finish_login(&oidc, &session, Some(state)).await,
+            Err((StatusCode::BAD_REQUEST, "No login in progress"))
+        );
+        assert_eq!(get_identity(&session).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_nonce() {
+        let (oidc, authorization) = oidc_login().await;
+        let session = new_session();
+        let state = start_login(&oidc, &authorization, &session).await;
+        authorization.lock().unwrap().nonce = "replayed".to_owned();
+
+        let (status, _) = finish_login(&oidc, &session, Some(state)).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(get_identity(&session).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_pkce_verifier() {
+        let (oidc, authorization) = oidc_login().await;
+        let session = new_session();
+        let state = start_login(&oidc, &authorization, &session).await;
+        let mut pending: PendingLogin = session.get(PENDING_LOGIN_KEY).await.unwrap().unwrap();
+        pending.pkce_verifier = PkceCodeChallenge::new_random_sha256().1.into_secret();
+        session.insert(PENDING_LOGIN_KEY, pending).await.unwrap();
+
+        let (status, _) = finish_login(&oidc, &session, Some(state)).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(get_identity(&session).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn provider_error_keeps_the_player() {
+        let (oidc, authorization) = oidc_login().await;
+        let session = new_session();
+        let state = start_login(&oidc, &authorization, &session).await;
+        let params = CallbackParams {
+            code: None,
+            state: Some(state),
+            error: Some("access_denied".to_owned()),
+        };
+        let redirect = callback(
+            State(oidc.clone()),
+            State(ServerState::default()),
+            session.clone(),
+            Query(params),
+        )
+        .await
+        .unwrap();
+        assert_eq!(location(redirect), "/rooms/team-1");
+        assert_eq!(get_identity(&session).await.unwrap(), None);
+    }
+}
@@ -1 +1,2 @@
 pub mod poker;
+pub mod toast;
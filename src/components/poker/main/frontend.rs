@@ -1,46 +1,135 @@
+use crate::components::{
+    poker::room::api::{
+        AccountInfo, CreateRoom, CreateTeam, ResetIdentity, RoomRef, TeamSummary, get_account,
+        list_teams, suggest_room_slug,
+    },
+    toast::use_toasts,
+};
 use leptos::either::Either;
 use leptos::prelude::*;
 
+/// Signing in and out with single sign-on, nothing if it's disabled
+#[component]
+fn Account(account: AccountInfo) -> impl IntoView {
+    match account.signed_in_as {
+        Some(name) => view! {
+            <form method="post" action="/auth/logout" class="flex items-center justify-end gap-2">
+                <span class="text-sm">"Signed in as " <b>{ name }</b></span>
+                <button type="submit" class="btn btn-ghost btn-sm">"Sign out"</button>
+            </form>
+        }
+        .into_any(),
+        None if account.login_enabled => view! {
+            <div class="flex justify-end">
+                <a href="/auth/login" rel="external" class="btn btn-sm">"Sign in"</a>
+            </div>
+        }
+        .into_any(),
+        None => ().into_any(),
+    }
+}
+
+/// Team rooms the player is a member of, nothing if there are none
+#[component]
+fn Teams(teams: Vec<TeamSummary>) -> impl IntoView {
+    (!teams.is_empty()).then(|| view! {
+        <h2 class="text-base md:text-lg lg:text-xl font-semibold mt-6 mb-2 text-center">"Your teams"</h2>
+        <ul class="menu bg-base-200 rounded-box w-full max-w-xs mx-auto my-3">
+            { teams.into_iter().map(|team| view! {
+                <li>
+                    <a href=format!("/rooms/{}", team.slug) class="flex justify-between">
+                        <span class="font-semibold">{ team.slug.clone() }</span>
+                        <span class="text-xs opacity-70">
+                            { format!("{} members, {} rounds", team.members, team.rounds) }
+                        </span>
+                    </a>
+                </li>
+            }).collect_view() }
+        </ul>
+    })
+}
+
 #[component]
 pub fn PickRoom() -> impl IntoView {
     let (room_id, set_room_id) = signal(String::new());
     let on_input = move |ev| {
         set_room_id(event_target_value(&ev));
     };
-    let parse_error = Memo::new(move |_| room_id.get().parse::<u64>().err());
+    let parse_error = Memo::new(move |_| room_id.get().parse::<RoomRef>().err());
 
     let on_submit = move |ev: leptos::ev::SubmitEvent| {
         use leptos::prelude::window;
 
         ev.prevent_default();
-        if parse_error.get().is_none() {
+        if let Ok(room_id) = room_id.get().parse::<RoomRef>() {
             window()
                 .location()
-                .set_href(&format!("/rooms/{}", room_id.get()))
+                .set_href(&format!("/rooms/{room_id}"))
                 .unwrap()
         }
     };
 
+    let toasts = use_toasts();
+    let suggest = Action::new(move |_: &()| async move {
+        match suggest_room_slug().await {
+            Ok(slug) => set_room_id(slug),
+            Err(e) => toasts.error(e.to_string()),
+        }
+    });
+
+    let account = Resource::new(|| (), |_| get_account());
+    let teams = Resource::new(|| (), |_| list_teams());
+    let create_room = ServerAction::<CreateRoom>::new();
+    let create_team = ServerAction::<CreateTeam>::new();
+    let reset_identity = ServerAction::<ResetIdentity>::new();
+    Effect::new(move || {
+        if let Some(Err(e)) = reset_identity.value().get() {
+            toasts.error(e.to_string());
+        }
+    });
+    let create_error = move || {
+        create_room
+            .value()
+            .get()
+            .and_then(Result::err)
+            .map(|e| e.to_string())
+    };
+    let create_team_error = move || {
+        create_team
+            .value()
+            .get()
+            .and_then(Result::err)
+            .map(|e| e.to_string())
+    };
+
     view! {
         <div class="max-w-4xl mx-auto px-8 sm:px-4 lg:px-6 pt-6">
-            <h1 class="text-base md:text-xl lg:text-3xl font-bold my-2 text-center">"Enter room id"</h1>
+            <Transition>
+                { move || account.get().and_then(Result::ok).map(|account| view! { <Account account/> }) }
+            </Transition>
+            <Transition>
+                { move || teams.get().and_then(Result::ok).map(|teams| view! { <Teams teams/> }) }
+            </Transition>
+            <h1 class="text-base md:text-xl lg:text-3xl font-bold my-2 text-center">"Enter room id or name"</h1>
             <form class="flex justify-center my-3" name="room_id" on:submit=on_submit>
                 <div class="flex mx-auto">
                     <input
                         type="text"
-                        placeholder="Room id"
+                        placeholder="Room id or name"
                         class="input input-bordered w-full max-w-xs"
                         prop:value=room_id
                         on:input=on_input
                     />
                     <div class="w-2 h-auto"></div>
+                    <button type="button" class="btn" on:click=move |_| { suggest.dispatch(()); }>"Random"</button>
+                    <div class="w-2 h-auto"></div>
                     { move || {
                         match parse_error.get() {
                             None => Either::Left(view! {
                                 <input type="submit" class="btn" value="Go!" />
                             }),
                             Some(e) => Either::Right(view! {
-                                <div class="tooltip tooltip-right before:whitespace-pre before:content-[attr(data-tip)]" data-tip=format!("Wrong room number: {:?}\n(u64 expected)", e.kind())>
+                                <div class="tooltip tooltip-right before:whitespace-pre before:content-[attr(data-tip)]" data-tip=format!("Wrong room name: {e}\n(a number or a name like brave-turing-42 expected)")>
                                     <input type="submit" class="btn btn-disabled" value="Nope" />
                                 </div>
                             }),
@@ -48,6 +137,66 @@ pub fn PickRoom() -> impl IntoView {
                     }}
                 </div>
             </form>
+            <h2 class="text-base md:text-lg lg:text-xl font-semibold mt-6 mb-2 text-center">"Or create a new one"</h2>
+            <ActionForm action=create_room attr:class="flex flex-col items-center gap-2 my-3">
+                <input
+                    type="text"
+                    name="name"
+                    placeholder="Room name (optional)"
+                    class="input input-bordered w-full max-w-xs"
+                />
+                <input
+                    type="text"
+                    name="deck"
+                    placeholder="Deck, e.g. 0.5, 1, 2, 3, 5 (optional)"
+                    class="input input-bordered w-full max-w-xs"
+                />
+                <input
+                    type="password"
+                    name="passcode"
+                    placeholder="Passcode for a private room (optional)"
+                    class="input input-bordered w-full max-w-xs"
+                />
+                <input type="submit" class="btn" value="Create" />
+                { move || create_error().map(|e| view! {
+                    <span class="label-text-alt text-error">{ e }</span>
+                })}
+            </ActionForm>
+            <h2 class="text-base md:text-lg lg:text-xl font-semibold mt-6 mb-2 text-center">"Or set up a room for your team"</h2>
+            <ActionForm action=create_team attr:class="flex flex-col items-center gap-2 my-3">
+                <input
+                    type="text"
+                    name="name"
+                    placeholder="Team name"
+                    required
+                    class="input input-bordered w-full max-w-xs"
+                />
+                <input
+                    type="text"
+                    name="deck"
+                    placeholder="Deck, e.g. 0.5, 1, 2, 3, 5 (optional)"
+                    class="input input-bordered w-full max-w-xs"
+                />
+                <input
+                    type="password"
+                    name="passcode"
+                    placeholder="Passcode for non-members (optional)"
+                    class="input input-bordered w-full max-w-xs"
+                />
+                <input type="submit" class="btn" value="Create team" />
+                { move || create_team_error().map(|e| view! {
+                    <span class="label-text-alt text-error">{ e }</span>
+                })}
+            </ActionForm>
+            <ActionForm action=reset_identity attr:class="flex justify-center mt-10">
+                <button
+                    type="submit"
+                    class="btn btn-ghost btn-sm"
+                    title="Get a new identity and leave all the rooms, useful on a shared computer"
+                >
+                    "Start over as a new player"
+                </button>
+            </ActionForm>
         </div>
     }
 }
@@ -1,50 +1,337 @@
+use http::StatusCode;
 use leptos::{prelude::*, server_fn::BoxedStream};
 use serde::{Deserialize, Serialize};
 use server_fn::{Websocket, codec::JsonEncoding};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
 
 use crate::if_backend;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct PlayerState {
+    /// Only known once revealed, for the other players
     pub(super) card: Option<u64>,
+    pub(super) voted: bool,
     pub(super) name: String,
+    /// Sits the rounds out and only watches
+    pub(super) spectator: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct PlayerGameState {
     pub(super) players: Vec<PlayerState>,
-    pub(super) cards: Vec<u64>,
+    pub(super) settings: RoomSettings,
     pub(super) self_state: PlayerState,
     pub(super) hidden: bool,
+    /// Whether the player is the room's host and may change its settings
+    pub(super) is_host: bool,
+    /// Seconds left until the round's timer reveals the cards
+    pub(super) time_left: Option<u32>,
+    /// Revealed cards of an anonymous room, lowest first. The cards of the
+    /// other players stay unknown then.
+    pub(super) anonymous_cards: Option<Vec<u64>>,
+    /// Latest reminder to vote the player hasn't followed yet
+    pub(super) nudge: Option<Nudge>,
+}
+
+/// Reminder to vote sent by another player
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Nudge {
+    /// Tells the reminders apart, so each one is shown once
+    pub id: u64,
+    pub from: String,
+}
+
+/// Who may reveal and hide the cards
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevealPermission {
+    #[default]
+    Everyone,
+    Host,
+}
+
+/// Configuration of a room, changed by its host. New fields have to be
+/// covered by the `Default`, team rooms keep their settings across restarts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomSettings {
+    /// Shown instead of the room's id or name
+    pub title: Option<String>,
+    pub cards: Vec<u64>,
+    pub reveal: RevealPermission,
+    /// Reveal the cards once every player has voted
+    pub auto_reveal: bool,
+    /// Seconds the players get to vote once the first card is down, the
+    /// cards are revealed when they run out
+    pub timer: Option<u32>,
+    pub allow_spectators: bool,
+    /// Reveal the cards without who played them
+    pub anonymous: bool,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            title: None,
+            cards: vec![],
+            reveal: Default::default(),
+            auto_reveal: false,
+            timer: None,
+            allow_spectators: true,
+            anonymous: false,
+        }
+    }
+}
+
+impl RoomSettings {
+    pub const MAX_TITLE_LEN: usize = 64;
+    pub const TIMER_RANGE: std::ops::RangeInclusive<u32> = 10..=3600;
+
+    /// Checks settings coming from a player, normalizing the title and deck
+    pub fn validate(mut self) -> Result<Self, ServerError> {
+        self.title = self
+            .title
+            .map(|title| title.trim().to_owned())
+            .filter(|title| !title.is_empty());
+        if self
+            .title
+            .as_ref()
+            .is_some_and(|title| title.chars().count() > Self::MAX_TITLE_LEN)
+        {
+            return Err(ServerError::invalid_input(
+                "title",
+                format!("Can't be longer than {} characters", Self::MAX_TITLE_LEN),
+            ));
+        }
+        self.cards.sort_unstable();
+        self.cards.dedup();
+        match self.cards.len() {
+            0 => Err(ServerError::invalid_input(
+                "deck",
+                "Has to contain at least one card",
+            ))?,
+            1..=32 => {}
+            _ => Err(ServerError::invalid_input(
+                "deck",
+                "Can't contain more than 32 cards",
+            ))?,
+        }
+        if self.cards.iter().any(|&card| card > 100_000_000) {
+            Err(ServerError::invalid_input(
+                "deck",
+                "Cards can't be over 1000000",
+            ))?;
+        }
+        if self
+            .timer
+            .is_some_and(|timer| !Self::TIMER_RANGE.contains(&timer))
+        {
+            return Err(ServerError::invalid_input(
+                "timer",
+                format!(
+                    "Has to be {} to {} seconds",
+                    Self::TIMER_RANGE.start(),
+                    Self::TIMER_RANGE.end()
+                ),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// A player's card in a revealed round
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Vote {
+    /// Not kept for rounds of anonymous rooms
+    pub name: Option<String>,
+    pub card: Option<u64>,
+}
+
+/// Revealed round of a team room
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Round {
+    /// Unix time in seconds
+    pub revealed_at: u64,
+    pub votes: Vec<Vote>,
+}
+
+/// Team as listed on the landing page of its members
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TeamSummary {
+    pub slug: String,
+    pub members: usize,
+    pub rounds: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TeamDetails {
+    /// Whether the player asking is a member
+    pub is_member: bool,
+    pub members: Vec<String>,
+    /// Latest round first
+    pub history: Vec<Round>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RoomEvent {
+    State(Box<PlayerGameState>),
+    /// The server is going down, the client is expected to reconnect
+    ServerRestarting,
+    /// The player has joined the room over another connection, which took
+    /// over this one
+    JoinedElsewhere,
+    /// The player was removed from the room, e.g. after starting over as a
+    /// new player
+    Removed,
 }
 
 if_backend! {
-    use super::backend::{Game, ServerState};
-    use crate::uid::{get_or_create_uid, get_uid};
+    use super::backend::{Game, GameInner, ServerState, TeamRound};
+    use crate::session_store::AnySessionStore;
+    use crate::auth::OidcLogin;
+    use crate::random_nickname::gen_nickname;
+    use crate::team_store::{Team, TeamMember};
+    use crate::uid::{
+        Identity, get_identity, get_or_create_identity, get_uid, reset_uid, set_display_name,
+    };
 
     use leptos_axum::{extract, ResponseOptions};
     use tower_sessions::Session;
-    use http::StatusCode;
-    use futures::{StreamExt, stream};
-    use tokio::select;
-    use tracing::{info, error, warn};
-    use std::sync::Arc;
+    use futures::{StreamExt, future, stream};
+    use tokio::{select, sync::{OwnedMutexGuard, watch}};
+    use tracing::{Instrument, Span, field, info, info_span, error, warn};
+    use std::{sync::Arc, time::Duration};
     use atomic_refcell::AtomicRefCell;
 
-    async fn get_game(room_id: u64) -> Result<Game, ServerError> {
+    /// Passcodes of the private rooms the player has entered, by room, the
+    /// latest last
+    const ROOM_PASSCODES_KEY: &str = "room_passcodes";
+    /// Sessions are small, the oldest rooms are forgotten first
+    const MAX_ROOM_PASSCODES: usize = 8;
+
+    /// Passcodes kept by earlier versions in another shape are dropped
+    async fn room_passcodes(session: &Session) -> Vec<(String, String)> {
+        session
+            .get(ROOM_PASSCODES_KEY)
+            .await
+            .inspect_err(|e| warn!("Failed to retrieve room passcodes: {e}"))
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    async fn remembered_passcode(session: &Session, room_id: &RoomRef) -> Option<String> {
+        let room = room_id.to_string();
+        room_passcodes(session)
+            .await
+            .into_iter()
+            .find_map(|(entered, passcode)| (entered == room).then_some(passcode))
+    }
+
+    async fn remember_passcode(
+        session: &Session,
+        room_id: &RoomRef,
+        passcode: String,
+    ) -> Result<(), ServerError> {
+        let room = room_id.to_string();
+        let mut passcodes = room_passcodes(session).await;
+        passcodes.retain(|(entered, _)| *entered != room);
+        passcodes.push((room, passcode));
+        let excess = passcodes.len().saturating_sub(MAX_ROOM_PASSCODES);
+        passcodes.drain(..excess);
+        session
+            .insert(ROOM_PASSCODES_KEY, passcodes)
+            .await
+            .map_err(|e| {
+                error!("Failed to save room passcode: {e}");
+                ServerError::Internal
+            })
+    }
+
+    async fn get_game(room_id: &RoomRef) -> Result<Game, ServerError> {
         let state = use_context::<ServerState>().expect("ServerState to be provided");
         state
             .get_game(room_id)
             .await
-            .ok_or_else(|| ServerError::new_custom("No such room"))
+            .ok_or(ServerError::RoomNotFound)
+    }
+
+    /// Locks the room on behalf of the caller, making sure they have been
+    /// admitted into it
+    async fn lock_game_as_member(
+        room_id: &RoomRef,
+    ) -> Result<(OwnedMutexGuard<GameInner>, u128), ServerError> {
+        let session = get_session().await?;
+        let uid = get_uid_server(&session).await?;
+        let game = get_game(room_id).await?.0.lock_owned().await;
+        if !game.is_member(uid) {
+            warn!("Player {uid} isn't admitted into room {room_id}");
+            return Err(ServerError::Forbidden);
+        }
+        Ok((game, uid))
+    }
+
+    /// Adds the round revealed in a team room to the team's history
+    async fn record_team_round(
+        state: &ServerState,
+        revealed: Option<TeamRound>,
+    ) -> Result<(), ServerError> {
+        let Some((slug, round)) = revealed else {
+            return Ok(());
+        };
+        state.record_round(&slug, round).await.map_err(|e| {
+            error!("Failed to record round of team {slug}: {e}");
+            ServerError::Internal
+        })
+    }
+
+    /// Reveals the cards when the round's timer runs out, unless the round
+    /// is over by then
+    fn schedule_reveal(state: ServerState, room_id: RoomRef, round: u64, after: Duration) {
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(after).await;
+                let Some(game) = state.get_game(&room_id).await else {
+                    return;
+                };
+                let revealed = game.0.lock().await.timer_expired(round);
+                let _ = record_team_round(&state, revealed).await;
+            }
+            .instrument(Span::current()),
+        );
+    }
+
+    /// Span around a server function call, `uid` gets recorded once the
+    /// caller is identified
+    fn server_fn_span(name: &'static str, room_id: Option<&RoomRef>) -> Span {
+        info_span!(
+            "server_fn",
+            server_fn = name,
+            room_id = room_id.map(field::display),
+            uid = field::Empty,
+        )
     }
 
     async fn get_session() -> Result<Session, ServerError> {
         extract().await.map_err(Into::into)
     }
 
-    fn set_status(code: StatusCode) {
-        expect_context::<ResponseOptions>().set_status(code);
+    /// Reflects the error in the response status
+    fn set_error_status(e: &ServerError) {
+        expect_context::<ResponseOptions>().set_status(e.status());
+    }
+
+    /// Runs the body of a server function in its span and reflects the error
+    /// in the response status
+    async fn run_server_fn<T>(
+        name: &'static str,
+        room_id: Option<&RoomRef>,
+        body: impl Future<Output = Result<T, ServerError>>,
+    ) -> Result<T, ServerError> {
+        body.instrument(server_fn_span(name, room_id))
+            .await
+            .inspect_err(set_error_status)
     }
 
     async fn get_uid_server(session: &Session) -> Result<u128, ServerError> {
@@ -52,37 +339,91 @@ if_backend! {
             .await
             .map_err(|e| {
                 error!("Failed to retrieve uid: {e}");
-                ServerError::new_custom("Internal server error")
+                ServerError::Internal
             })?.ok_or_else(|| {
                 warn!("Connection without uid");
-                set_status(StatusCode::UNAUTHORIZED);
-                ServerError::new_custom("Unauthorized")
+                ServerError::Unauthorized
+            })
+            .inspect(|&uid| {
+                Span::current().record("uid", uid);
             })
     }
 
-    async fn get_or_create_uid_server(session: &Session) -> Result<u128, ServerError> {
-        get_or_create_uid(session).await.map_err(|e| {
-            error!("Failed to get uid: {e}");
-            ServerError::new_custom("Internal server error")
-        })
+    fn get_session_store() -> AnySessionStore {
+        use_context::<AnySessionStore>().expect("AnySessionStore to be provided")
+    }
+
+    async fn get_or_create_identity_server(session: &Session) -> Result<Identity, ServerError> {
+        get_or_create_identity(session, &get_session_store())
+            .await
+            .map_err(|e| {
+                error!("Failed to get identity: {e}");
+                ServerError::Internal
+            })
+            .inspect(|identity| {
+                Span::current().record("uid", identity.uid);
+            })
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Errors returned by the server functions. They travel as
+/// `{"code": "room_not_found", ...}`, the codes are stable and can be relied
+/// upon by clients
+#[derive(Clone, Debug, PartialEq, Error, Serialize, Deserialize)]
+#[serde(tag = "code", content = "details", rename_all = "snake_case")]
 pub enum ServerError {
-    ServerFnError(ServerFnErrorErr),
-    Custom(String),
+    #[error("No such room")]
+    RoomNotFound,
+    #[error("Your session has expired, reload the page")]
+    Unauthorized,
+    #[error("You haven't joined this room")]
+    Forbidden,
+    #[error("Only the host of the room can do that")]
+    HostOnly,
+    #[error("Wrong passcode")]
+    WrongPasscode,
+    #[error("Room is full")]
+    RoomFull,
+    #[error("Room with this name already exists")]
+    RoomExists,
+    #[error("Invalid name: {0}")]
+    InvalidName(String),
+    #[error("Invalid {field}: {reason}")]
+    InvalidInput { field: String, reason: String },
+    #[error("Too many requests, slow down a bit")]
+    RateLimited,
+    #[error("Internal server error")]
+    Internal,
+    /// Failures of the transport itself, e.g. the server being unreachable
+    #[error("{0}")]
+    ServerFn(ServerFnErrorErr),
 }
 
 impl ServerError {
-    pub fn new_custom(s: impl Into<String>) -> Self {
-        Self::Custom(s.into())
+    pub fn invalid_input(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::InvalidInput {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// HTTP status the error is reported with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::RoomNotFound => StatusCode::NOT_FOUND,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden | Self::HostOnly | Self::WrongPasscode => StatusCode::FORBIDDEN,
+            Self::RoomFull | Self::RoomExists => StatusCode::CONFLICT,
+            Self::InvalidName(_) | Self::InvalidInput { .. } => StatusCode::BAD_REQUEST,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::Internal | Self::ServerFn(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 }
 
 impl From<ServerFnErrorErr> for ServerError {
     fn from(value: ServerFnErrorErr) -> Self {
-        Self::ServerFnError(value)
+        Self::ServerFn(value)
     }
 }
 
@@ -108,29 +449,154 @@ pub fn check_username(s: &str) -> Result<(), String> {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub fn check_passcode(s: &str) -> Result<(), String> {
+    if !(4..=64).contains(&s.len()) {
+        Err("Has to be 4 to 64 characters long")?;
+    }
+    if !s
+        .chars()
+        .all(|c| matches!(c, '0'..='9' | 'a'..='z' | 'A'..='Z' | '-' | '_'))
+    {
+        Err("Allowed characters: a-z A-Z 0-9 _-".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn check_room_slug(s: &str) -> Result<(), String> {
+    if !(3..=48).contains(&s.len()) {
+        Err("Has to be 3 to 48 characters long")?;
+    }
+    if !s.chars().any(|c| c.is_ascii_lowercase()) {
+        Err("Has to contain at least one letter")?;
+    }
+    if s.starts_with('-') || s.ends_with('-') {
+        Err("Can't start or end with a dash")?;
+    }
+    if !s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='z' | '-')) {
+        Err("Allowed characters: a-z 0-9 -".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+/// Parses a deck like `0.5, 1, 2, 3` into cards (stored in hundredths)
+pub fn parse_deck(s: &str) -> Result<Vec<u64>, String> {
+    let mut cards = s
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|v| !v.is_empty())
+        .map(|v| match v.parse::<f64>() {
+            Ok(card) if card.is_finite() && (0. ..=1e6).contains(&card) => {
+                Ok((card * 100.).round() as u64)
+            }
+            _ => Err(format!("Not a valid card: {v}")),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    cards.sort_unstable();
+    cards.dedup();
+    match cards.len() {
+        0 => Err("Has to contain at least one card".to_owned()),
+        1..=32 => Ok(cards),
+        _ => Err("Can't contain more than 32 cards".to_owned()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Error)]
+#[error("{0}")]
+pub struct InvalidRoomRef(String);
+
+/// Room reference as it appears in the url: either a bare numeric room id
+/// (the way rooms used to be addressed) or a human-readable slug.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum RoomRef {
+    Id(u64),
+    Slug(String),
+}
+
+impl FromStr for RoomRef {
+    type Err = InvalidRoomRef;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse() {
+            return Ok(Self::Id(id));
+        }
+        let slug = s.trim().to_ascii_lowercase();
+        check_room_slug(&slug).map_err(InvalidRoomRef)?;
+        Ok(Self::Slug(slug))
+    }
+}
+
+impl TryFrom<String> for RoomRef {
+    type Error = InvalidRoomRef;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RoomRef> for String {
+    fn from(value: RoomRef) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for RoomRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomRef::Id(id) => write!(f, "{id}"),
+            RoomRef::Slug(slug) => f.write_str(slug),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum UserStreamRequest {
-    SetRoom(u64),
+    SetRoom {
+        room: RoomRef,
+        passcode: Option<String>,
+    },
 }
 
 #[server(protocol = Websocket<JsonEncoding, JsonEncoding>, prefix = "/api")]
 pub async fn subscribe_to_room(
     inp: BoxedStream<UserStreamRequest, ServerError>,
-) -> Result<BoxedStream<PlayerGameState, ServerError>, ServerError> {
+) -> Result<BoxedStream<RoomEvent, ServerError>, ServerError> {
     let mut inp = inp;
-    let session = get_session().await?;
-    let uid = get_or_create_uid_server(&session).await?;
+    let span = info_span!(
+        "subscribe_to_room",
+        room_id = field::Empty,
+        uid = field::Empty
+    );
+    let session = get_session()
+        .instrument(span.clone())
+        .await
+        .inspect_err(set_error_status)?;
+    let identity = get_or_create_identity_server(&session)
+        .instrument(span.clone())
+        .await
+        .inspect_err(set_error_status)?;
+    let uid = identity.uid;
 
-    let (tx, rx) = tokio::sync::watch::channel(PlayerGameState::default());
+    let (tx, rx) = watch::channel(Ok(RoomEvent::State(Default::default())));
     let rx = Arc::new(AtomicRefCell::new(rx));
 
     let state = use_context::<ServerState>().expect("ServerState to be provided");
 
+    let mut shutdown = state.shutdown_signal();
+
     tokio::spawn(async move {
-        let (_tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let mut rx: Option<watch::Receiver<PlayerGameState>> = None;
+        // Tells a takeover from a removal once the updates stop
+        let mut joined: Option<Game> = None;
+        metrics::gauge!("players_connected").increment(1);
 
         loop {
             select! {
+                _ = async { shutdown.wait_for(|&v| v).await.is_ok() } => {
+                    let _ = tx.send(Ok(RoomEvent::ServerRestarting));
+                    break;
+                }
                 cmd = inp.next() => {
                     let cmd = match cmd {
                         Some(Ok(v)) => v,
@@ -140,26 +606,83 @@ pub async fn subscribe_to_room(
                         },
                         None => break,
                     };
-                    let room = match cmd {
-                        UserStreamRequest::SetRoom(room) => room,
+                    let UserStreamRequest::SetRoom { room, passcode } = cmd;
+                    Span::current().record("room_id", field::display(&room));
+                    let passcode = match passcode {
+                        Some(passcode) => Some(passcode),
+                        None => remembered_passcode(&session, &room).await,
                     };
 
-                    let game = state.get_or_create_game(room).await;
+                    let game = match state.open_game(&room, uid, passcode.as_deref()).await {
+                        Ok(Some(game)) => game,
+                        Ok(None) => {
+                            warn!("Player {uid} provided wrong passcode for team room {room}");
+                            let _ = tx.send(Err(ServerError::WrongPasscode));
+                            break;
+                        }
+                        Err(e) => {
+                            error!("Failed to load room {room}: {e}");
+                            let _ = tx.send(Err(ServerError::Internal));
+                            break;
+                        }
+                    };
+                    let game_handle = game.clone();
+                    let mut game = game.0.lock().await;
+                    if !game.admit(uid, passcode.as_deref()) {
+                        warn!("Player {uid} provided wrong passcode for room {room}");
+                        let _ = tx.send(Err(ServerError::WrongPasscode));
+                        break;
+                    }
 
-                    rx = game.0.lock().await.new_player(uid).await;
+                    rx = game.new_player(uid, identity.display_name.clone());
+                    if rx.is_none() {
+                        warn!("Player {uid} can't join room {room}: room is full");
+                        let _ = tx.send(Err(ServerError::RoomFull));
+                        break;
+                    }
+                    // Entering the passcode of a team room makes the player a
+                    // member, public team rooms are joined explicitly
+                    let joined_team = game.is_private().then(|| game.join_team(uid)).flatten();
+                    drop(game);
+                    joined = Some(game_handle);
+                    if let Some((slug, name)) = joined_team
+                        && let Err(e) = state.add_team_member(&slug, uid, name).await
+                    {
+                        error!("Failed to add player {uid} to team {slug}: {e}");
+                    }
                 }
-                state = rx.recv() => {
+                state = async {
+                    match &mut rx {
+                        Some(rx) => rx.changed().await.map(|_| rx.borrow_and_update().clone()),
+                        None => future::pending().await,
+                    }
+                } => {
                     let state = match state {
-                        Some(v) => v,
-                        None => break,
+                        Ok(v) => v,
+                        Err(_) => {
+                            // A new connection replaces the player, a removal drops them
+                            let taken_over = match &joined {
+                                Some(game) => game.0.lock().await.has_player(uid),
+                                None => false,
+                            };
+                            if taken_over {
+                                info!("Player {uid} joined from elsewhere, closing connection");
+                                let _ = tx.send(Ok(RoomEvent::JoinedElsewhere));
+                            } else {
+                                info!("Player {uid} was removed from the room, closing connection");
+                                let _ = tx.send(Ok(RoomEvent::Removed));
+                            }
+                            break;
+                        }
                     };
-                    if tx.send(state).is_err() {
+                    if tx.send(Ok(RoomEvent::State(Box::new(state)))).is_err() {
                         break;
                     }
                 }
             }
         }
-    });
+        metrics::gauge!("players_connected").decrement(1);
+    }.instrument(span));
 
     Ok(stream::iter(0..)
         .filter_map(move |_| {
@@ -169,53 +692,463 @@ pub async fn subscribe_to_room(
                 if rx.changed().await.is_err() {
                     return None;
                 }
-                let v = PlayerGameState::clone(&rx.borrow_and_update());
-                Some(Ok(v))
+                let v = rx.borrow_and_update().clone();
+                metrics::counter!("websocket_messages_sent_total").increment(1);
+                Some(v)
             }
         })
         .into())
 }
 
+/// Admits the caller into a private room. The passcode is kept in the
+/// session, so it never has to travel in a url.
+#[server(name = JoinRoom, prefix = "/api")]
+pub async fn join_room(room_id: RoomRef, passcode: String) -> Result<(), ServerError> {
+    run_server_fn("join_room", Some(&room_id), async {
+        check_passcode(&passcode).map_err(|_| ServerError::WrongPasscode)?;
+        let session = get_session().await?;
+        let identity = get_or_create_identity_server(&session).await?;
+        let state = use_context::<ServerState>().expect("ServerState to be provided");
+        // Rooms that aren't up yet check it once they're opened
+        let admitted = match state.get_game(&room_id).await {
+            Some(game) => game.0.lock().await.admit(identity.uid, Some(&passcode)),
+            None => true,
+        };
+        if !admitted {
+            warn!("Player {} provided wrong passcode", identity.uid);
+            return Err(ServerError::WrongPasscode);
+        }
+        remember_passcode(&session, &room_id, passcode).await?;
+        leptos_axum::redirect(&format!("/rooms/{room_id}"));
+        Ok(())
+    })
+    .await
+}
+
+/// Makes the caller a member of the room's team, the room is then listed
+/// among their teams
+#[server(name = JoinTeam, prefix = "/api")]
+pub async fn join_team(room_id: RoomRef) -> Result<(), ServerError> {
+    run_server_fn("join_team", Some(&room_id), async {
+        let (mut game, uid) = lock_game_as_member(&room_id).await?;
+        let joined = game.join_team(uid);
+        drop(game);
+        let Some((slug, name)) = joined else {
+            return Ok(());
+        };
+        let state = use_context::<ServerState>().expect("ServerState to be provided");
+        state.add_team_member(&slug, uid, name).await.map_err(|e| {
+            error!("Failed to add player {uid} to team {slug}: {e}");
+            ServerError::Internal
+        })
+    })
+    .await
+}
+
 #[server(name = PlaceBet, prefix = "/api")]
-pub async fn place_bet(room_id: u64, card: Option<u64>) -> Result<(), ServerError> {
-    let session = get_session().await?;
-    let uid = get_uid_server(&session).await?;
-    get_game(room_id)
-        .await?
-        .0
-        .lock()
-        .await
-        .place_bet(uid, card)
-        .await;
-    Ok(())
+pub async fn place_bet(room_id: RoomRef, card: Option<u64>) -> Result<(), ServerError> {
+    run_server_fn("place_bet", Some(&room_id), async {
+        let (mut game, uid) = lock_game_as_member(&room_id).await?;
+        let outcome = game.place_bet(uid, card);
+        drop(game);
+        let state = use_context::<ServerState>().expect("ServerState to be provided");
+        if let Some((round, after)) = outcome.timer {
+            schedule_reveal(state.clone(), room_id.clone(), round, after);
+        }
+        record_team_round(&state, outcome.revealed).await
+    })
+    .await
 }
 
 #[server(name = Reveal, prefix = "/api")]
-pub async fn reveal(room_id: u64) -> Result<(), ServerError> {
-    get_game(room_id).await?.0.lock().await.reveal().await;
-    Ok(())
+pub async fn reveal(room_id: RoomRef) -> Result<(), ServerError> {
+    run_server_fn("reveal", Some(&room_id), async {
+        let (mut game, uid) = lock_game_as_member(&room_id).await?;
+        if !game.may_reveal(uid) {
+            return Err(ServerError::HostOnly);
+        }
+        let revealed = game.reveal();
+        drop(game);
+        let state = use_context::<ServerState>().expect("ServerState to be provided");
+        record_team_round(&state, revealed).await
+    })
+    .await
+}
+
+/// Reminds the players who haven't voted yet to do so
+#[server(name = NudgePlayers, prefix = "/api")]
+pub async fn nudge_players(room_id: RoomRef) -> Result<(), ServerError> {
+    run_server_fn("nudge_players", Some(&room_id), async {
+        let (mut game, uid) = lock_game_as_member(&room_id).await?;
+        if !game.nudge(uid) {
+            return Err(ServerError::RateLimited);
+        }
+        Ok(())
+    })
+    .await
 }
 
 #[server(name = Hide, prefix = "/api")]
-pub async fn hide(room_id: u64) -> Result<(), ServerError> {
-    get_game(room_id).await?.0.lock().await.hide().await;
-    Ok(())
+pub async fn hide(room_id: RoomRef) -> Result<(), ServerError> {
+    run_server_fn("hide", Some(&room_id), async {
+        let (mut game, uid) = lock_game_as_member(&room_id).await?;
+        if !game.may_reveal(uid) {
+            return Err(ServerError::HostOnly);
+        }
+        game.hide();
+        Ok(())
+    })
+    .await
+}
+
+/// Changes the settings of the room, only its host can. Team rooms keep them
+/// for the next time.
+#[server(name = UpdateRoomSettings, prefix = "/api")]
+pub async fn update_room_settings(
+    room_id: RoomRef,
+    settings: RoomSettings,
+) -> Result<(), ServerError> {
+    run_server_fn("update_room_settings", Some(&room_id), async {
+        let settings = settings.validate()?;
+        let (mut game, uid) = lock_game_as_member(&room_id).await?;
+        if !game.is_host(uid) {
+            return Err(ServerError::HostOnly);
+        }
+        let team = game.set_settings(settings.clone());
+        drop(game);
+        if let Some(slug) = team {
+            let state = use_context::<ServerState>().expect("ServerState to be provided");
+            state
+                .save_team_settings(&slug, settings)
+                .await
+                .map_err(|e| {
+                    error!("Failed to save settings of team {slug}: {e}");
+                    ServerError::Internal
+                })?;
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Switches between voting and only watching the rounds
+#[server(name = SetSpectator, prefix = "/api")]
+pub async fn set_spectator(room_id: RoomRef, spectator: bool) -> Result<(), ServerError> {
+    run_server_fn("set_spectator", Some(&room_id), async {
+        let (mut game, uid) = lock_game_as_member(&room_id).await?;
+        if !game.set_spectator(uid, spectator) {
+            return Err(ServerError::invalid_input(
+                "spectator",
+                "The room doesn't allow spectators",
+            ));
+        }
+        Ok(())
+    })
+    .await
 }
 
 #[server(name = SetName, prefix = "/api")]
-pub async fn set_name(room_id: u64, name: String) -> Result<(), ServerError> {
-    check_username(&name).map_err(|e| {
-        set_status(StatusCode::BAD_REQUEST);
-        ServerError::Custom(e)
-    })?;
-    let session = get_session().await?;
-    let uid = get_uid_server(&session).await?;
-    get_game(room_id)
-        .await?
-        .0
-        .lock()
-        .await
-        .set_name(uid, name)
-        .await;
-    Ok(())
+pub async fn set_name(room_id: RoomRef, name: String) -> Result<(), ServerError> {
+    run_server_fn("set_name", Some(&room_id), async {
+        check_username(&name).map_err(ServerError::InvalidName)?;
+        let (mut game, uid) = lock_game_as_member(&room_id).await?;
+        game.set_name(uid, name.clone());
+        drop(game);
+        let session = get_session().await?;
+        set_display_name(&session, &get_session_store(), name)
+            .await
+            .map_err(|e| {
+                error!("Failed to save display name: {e}");
+                ServerError::Internal
+            })
+    })
+    .await
+}
+
+#[server(name = CreateRoom, prefix = "/api")]
+pub async fn create_room(
+    name: Option<String>,
+    deck: Option<String>,
+    passcode: Option<String>,
+) -> Result<String, ServerError> {
+    run_server_fn("create_room", None, async move {
+        let name = name
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty());
+        if let Some(name) = &name {
+            check_room_slug(name).map_err(ServerError::InvalidName)?;
+        }
+        let cards = deck
+            .filter(|deck| !deck.trim().is_empty())
+            .map(|deck| parse_deck(&deck))
+            .transpose()
+            .map_err(|e| ServerError::invalid_input("deck", e))?;
+        let passcode = passcode.filter(|passcode| !passcode.is_empty());
+        if let Some(passcode) = &passcode {
+            check_passcode(passcode).map_err(|e| ServerError::invalid_input("passcode", e))?;
+        }
+
+        let session = get_session().await?;
+        let identity = get_or_create_identity_server(&session).await?;
+        let state = use_context::<ServerState>().expect("ServerState to be provided");
+        let room = state
+            .create_game(name, cards, passcode.clone(), identity.uid)
+            .await
+            .map_err(|e| {
+                error!("Failed to check for teams: {e}");
+                ServerError::Internal
+            })?
+            .ok_or(ServerError::RoomExists)?;
+        Span::current().record("room_id", field::display(&room));
+        // The creator is admitted already, the passcode stays out of the url
+        leptos_axum::redirect(&format!("/rooms/{room}"));
+        Ok(room.to_string())
+    })
+    .await
+}
+
+/// Starts over as a new player: the caller gets a fresh uid and the old one
+/// is removed from every room
+#[server(name = ResetIdentity, prefix = "/api")]
+pub async fn reset_identity() -> Result<(), ServerError> {
+    run_server_fn("reset_identity", None, async move {
+        let session = get_session().await?;
+        let old_uid = get_uid(&session).await.map_err(|e| {
+            error!("Failed to retrieve uid: {e}");
+            ServerError::Internal
+        })?;
+        let uid = reset_uid(&session).await.map_err(|e| {
+            error!("Failed to reset uid: {e}");
+            ServerError::Internal
+        })?;
+        Span::current().record("uid", uid);
+        if let Some(old_uid) = old_uid {
+            let state = use_context::<ServerState>().expect("ServerState to be provided");
+            state.forget_player(old_uid).await;
+            info!("Player {old_uid} reset their identity");
+        }
+        leptos_axum::redirect("/");
+        Ok(())
+    })
+    .await
+}
+
+#[server(name = SuggestRoomSlug, prefix = "/api")]
+pub async fn suggest_room_slug() -> Result<String, ServerError> {
+    run_server_fn("suggest_room_slug", None, async move {
+        let state = use_context::<ServerState>().expect("ServerState to be provided");
+        Ok(state.generate_slug().await)
+    })
+    .await
+}
+
+/// Whether the player can sign in with single sign-on and as whom they are
+/// signed in
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AccountInfo {
+    pub login_enabled: bool,
+    pub signed_in_as: Option<String>,
+}
+
+#[server(name = GetAccount, prefix = "/api")]
+pub async fn get_account() -> Result<AccountInfo, ServerError> {
+    run_server_fn("get_account", None, async move {
+        let login_enabled = use_context::<OidcLogin>().is_some_and(|oidc| oidc.is_enabled());
+        let session = get_session().await?;
+        let identity = get_identity(&session).await.map_err(|e| {
+            error!("Failed to retrieve identity: {e}");
+            ServerError::Internal
+        })?;
+        let signed_in_as = identity.and_then(|identity| {
+            let account = identity.account?;
+            Some(identity.display_name.unwrap_or(account.subject))
+        });
+        Ok(AccountInfo {
+            login_enabled,
+            signed_in_as,
+        })
+    })
+    .await
+}
+
+/// Sets up a recurring room for a team. The creator is its first member,
+/// everyone joining the room becomes one too.
+#[server(name = CreateTeam, prefix = "/api")]
+pub async fn create_team(
+    name: String,
+    deck: Option<String>,
+    passcode: Option<String>,
+) -> Result<String, ServerError> {
+    run_server_fn("create_team", None, async move {
+        let slug = name.trim().to_ascii_lowercase();
+        check_room_slug(&slug).map_err(ServerError::InvalidName)?;
+        let cards = deck
+            .filter(|deck| !deck.trim().is_empty())
+            .map(|deck| parse_deck(&deck))
+            .transpose()
+            .map_err(|e| ServerError::invalid_input("deck", e))?;
+        let passcode = passcode.filter(|passcode| !passcode.is_empty());
+        if let Some(passcode) = &passcode {
+            check_passcode(passcode).map_err(|e| ServerError::invalid_input("passcode", e))?;
+        }
+
+        let session = get_session().await?;
+        let identity = get_or_create_identity_server(&session).await?;
+        let state = use_context::<ServerState>().expect("ServerState to be provided");
+        let mut settings = state.default_settings().await;
+        if let Some(cards) = cards {
+            settings.cards = cards;
+        }
+        let team = Team {
+            slug: slug.clone(),
+            members: vec![TeamMember {
+                uid: identity.uid,
+                name: identity
+                    .display_name
+                    .unwrap_or_else(|| gen_nickname(identity.uid)),
+            }],
+            settings,
+            passcode,
+            history: vec![],
+        };
+        let created = state.create_team(team).await.map_err(|e| {
+            error!("Failed to create team: {e}");
+            ServerError::Internal
+        })?;
+        if !created {
+            return Err(ServerError::RoomExists);
+        }
+        Span::current().record("room_id", &slug);
+        info!("Player {} created team {slug}", identity.uid);
+        leptos_axum::redirect(&format!("/rooms/{slug}"));
+        Ok(slug)
+    })
+    .await
+}
+
+/// Teams the caller is a member of
+#[server(name = ListTeams, prefix = "/api")]
+pub async fn list_teams() -> Result<Vec<TeamSummary>, ServerError> {
+    run_server_fn("list_teams", None, async move {
+        let session = get_session().await?;
+        let Some(uid) = get_uid(&session).await.map_err(|e| {
+            error!("Failed to retrieve uid: {e}");
+            ServerError::Internal
+        })?
+        else {
+            return Ok(vec![]);
+        };
+        let state = use_context::<ServerState>().expect("ServerState to be provided");
+        state.list_teams(uid).await.map_err(|e| {
+            error!("Failed to list teams: {e}");
+            ServerError::Internal
+        })
+    })
+    .await
+}
+
+/// Members and history of the room's team, `None` for rooms without a team.
+/// Teams with a passcode only show them to their members.
+#[server(name = GetTeamDetails, prefix = "/api")]
+pub async fn get_team_details(room_id: RoomRef) -> Result<Option<TeamDetails>, ServerError> {
+    run_server_fn("get_team_details", Some(&room_id), async {
+        let RoomRef::Slug(slug) = &room_id else {
+            return Ok(None);
+        };
+        let state = use_context::<ServerState>().expect("ServerState to be provided");
+        let Some(team) = state.get_team(slug).await.map_err(|e| {
+            error!("Failed to load team {slug}: {e}");
+            ServerError::Internal
+        })?
+        else {
+            return Ok(None);
+        };
+        let session = get_session().await?;
+        let uid = get_uid(&session).await.map_err(|e| {
+            error!("Failed to retrieve uid: {e}");
+            ServerError::Internal
+        })?;
+        if team.passcode.is_some() && !uid.is_some_and(|uid| team.is_member(uid)) {
+            return Err(ServerError::Forbidden);
+        }
+        Ok(Some(team.details(uid)))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_ref_from_numbers_and_slugs() {
+        assert_eq!("42".parse(), Ok(RoomRef::Id(42)));
+        assert_eq!(
+            " Brave-Turing-42 ".parse(),
+            Ok(RoomRef::Slug("brave-turing-42".to_owned()))
+        );
+        // Too big for an id, and without letters no slug either
+        assert!("18446744073709551616".parse::<RoomRef>().is_err());
+        assert!("-42".parse::<RoomRef>().is_err());
+        assert_eq!(RoomRef::Slug("team".to_owned()).to_string(), "team");
+        assert_eq!(RoomRef::Id(42).to_string(), "42");
+    }
+
+    #[test]
+    fn room_slugs_are_checked() {
+        assert_eq!(check_room_slug("abc"), Ok(()));
+        assert_eq!(check_room_slug("sprint-42"), Ok(()));
+        assert_eq!(check_room_slug(&"a".repeat(48)), Ok(()));
+        // Only numbers are reserved for room ids
+        assert!(check_room_slug("123").is_err());
+        assert!(check_room_slug("12-34").is_err());
+        assert!(check_room_slug("ab").is_err());
+        assert!(check_room_slug(&"a".repeat(49)).is_err());
+        assert!(check_room_slug("-team").is_err());
+        assert!(check_room_slug("team-").is_err());
+        for invalid in ["Team", "te am", "te_am", "tea/m", "tëam", "team?x=1"] {
+            assert!(check_room_slug(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[cfg(feature = "ssr")]
+    #[tokio::test]
+    async fn oldest_passcodes_are_forgotten_first() {
+        use crate::session_store::MemorySessionStore;
+
+        let store = AnySessionStore::Memory(MemorySessionStore::default());
+        let session = Session::new(None, Arc::new(store), None);
+        // Kept in another shape before
+        let legacy: std::collections::HashMap<_, _> = [("0", "old")].into();
+        session.insert(ROOM_PASSCODES_KEY, legacy).await.unwrap();
+        assert_eq!(remembered_passcode(&session, &RoomRef::Id(0)).await, None);
+
+        for id in 0..MAX_ROOM_PASSCODES as u64 {
+            remember_passcode(&session, &RoomRef::Id(id), format!("passcode-{id}"))
+                .await
+                .unwrap();
+        }
+        // Entering a room again makes it the latest
+        remember_passcode(&session, &RoomRef::Id(0), "again".to_owned())
+            .await
+            .unwrap();
+        remember_passcode(&session, &RoomRef::Id(100), "new".to_owned())
+            .await
+            .unwrap();
+        let session = &session;
+        let remembered = |id| async move { remembered_passcode(session, &RoomRef::Id(id)).await };
+        assert_eq!(remembered(0).await.as_deref(), Some("again"));
+        assert_eq!(remembered(1).await, None);
+        assert_eq!(remembered(2).await.as_deref(), Some("passcode-2"));
+        assert_eq!(remembered(100).await.as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn room_ref_round_trips_through_json() {
+        for room in [RoomRef::Id(7), RoomRef::Slug("team".to_owned())] {
+            let json = serde_json::to_string(&room).unwrap();
+            assert_eq!(serde_json::from_str::<RoomRef>(&json).unwrap(), room);
+        }
+        assert!(serde_json::from_str::<RoomRef>("\"a b\"").is_err());
+    }
 }
@@ -1,20 +1,143 @@
-use crate::random_nickname::gen_nickname;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{
-    Mutex as AsyncMutex, RwLock as AsyncRwLock,
-    mpsc::{self},
+use crate::{
+    random_nickname::{gen_nickname, gen_slug},
+    team_store::{Team, TeamStore, TeamStoreError},
 };
+use rand::random;
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use subtle::ConstantTimeEq;
+use tokio::sync::{Mutex as AsyncMutex, RwLock as AsyncRwLock, watch};
+
+use super::api::{
+    Nudge, PlayerGameState, PlayerState, RevealPermission, RoomRef, RoomSettings, Round,
+    TeamSummary, Vote,
+};
+
+/// How often the players of a room can be nudged to vote
+const NUDGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Compares in constant time, so the passcode can't be guessed from response
+/// times. Rooms without a passcode match none.
+fn passcode_matches(expected: Option<&str>, provided: Option<&str>) -> bool {
+    match (expected, provided) {
+        (Some(expected), Some(provided)) => {
+            expected.as_bytes().ct_eq(provided.as_bytes()).into()
+        }
+        _ => false,
+    }
+}
+
+/// Settings newly created rooms start with
+#[derive(Debug, Clone)]
+pub struct RoomDefaults {
+    pub cards: Vec<u64>,
+    pub max_players: usize,
+    /// How many rounds team rooms remember
+    pub team_history: usize,
+}
+
+impl RoomDefaults {
+    fn settings(&self) -> RoomSettings {
+        RoomSettings {
+            cards: self.cards.clone(),
+            ..Default::default()
+        }
+    }
+}
 
-use super::api::{PlayerGameState, PlayerState};
+impl Default for RoomDefaults {
+    fn default() -> Self {
+        Self {
+            cards: vec![50, 100, 200, 300, 500, 800, 1300, 2100],
+            max_players: 50,
+            team_history: 100,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct ServerState {
     game_states: Arc<AsyncRwLock<GameStates>>,
+    shutdown: Arc<watch::Sender<bool>>,
+    teams: TeamStore,
+    /// Rounds the team store failed to take, retried on shutdown
+    unsaved_rounds: Arc<Mutex<Vec<TeamRound>>>,
 }
 
 impl ServerState {
-    pub fn new() -> Self {
-        Default::default()
+    pub fn new(defaults: RoomDefaults, teams: TeamStore) -> Self {
+        Self {
+            game_states: Arc::new(AsyncRwLock::new(GameStates {
+                defaults,
+                ..Default::default()
+            })),
+            shutdown: Default::default(),
+            teams,
+            unsaved_rounds: Default::default(),
+        }
+    }
+
+    /// Tells every connected player that the server is going down, waits for
+    /// their subscriptions to wind down and saves the team rooms. Other rooms
+    /// only live in memory and are dropped.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.shutdown.closed().await;
+        let rooms = self.game_states.read().await.games.len();
+        tracing::info!("All players notified, dropping {rooms} rooms");
+        self.persist_teams().await;
+    }
+
+    /// Saves what the team rooms have that their teams may lack: the settings,
+    /// the members and the rounds that failed to be recorded
+    async fn persist_teams(&self) {
+        let games: Vec<Game> = self.game_states.read().await.games.values().cloned().collect();
+        for game in games {
+            let (slug, settings, members) = {
+                let game = game.0.lock().await;
+                let Some(team) = &game.team else {
+                    continue;
+                };
+                let members: Vec<(u128, String)> = team
+                    .members
+                    .iter()
+                    .map(|&uid| {
+                        let name = game.players.get(&uid).map(|player| player.name.clone());
+                        (uid, name.unwrap_or_else(|| gen_nickname(uid)))
+                    })
+                    .collect();
+                (team.slug.clone(), game.settings.clone(), members)
+            };
+            let saved = self
+                .teams
+                .update(&slug, |team| {
+                    let mut changed = team.settings != settings;
+                    team.settings = settings.clone();
+                    for (uid, name) in &members {
+                        changed |= team.add_member(*uid, name.clone());
+                    }
+                    changed
+                })
+                .await;
+            if let Err(e) = saved {
+                tracing::error!("Failed to save team {slug} on shutdown: {e}");
+            }
+        }
+
+        let rounds = mem::take(&mut *self.unsaved_rounds.lock().unwrap());
+        for (slug, round) in rounds {
+            if let Err(e) = self.record_round(&slug, round).await {
+                tracing::error!("Failed to record round of team {slug} on shutdown: {e}");
+            }
+        }
+    }
+
+    pub(super) fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
     }
 
     // TODO: Support stale rooms removal
@@ -22,34 +145,239 @@ impl ServerState {
     //     self.game_states.write().await.remove_game(room_id).await
     // }
 
-    pub(super) async fn get_game(&self, room_id: u64) -> Option<Game> {
+    pub(super) async fn get_game(&self, room_id: &RoomRef) -> Option<Game> {
         self.game_states.read().await.get_game(room_id).await
     }
 
-    pub(super) async fn get_or_create_game(&self, room_id: u64) -> Game {
+    /// Brings the room up for the player. Team rooms are brought back with the
+    /// team's settings, but only for those the team admits, `None` is returned
+    /// for anyone else. Any other room starts afresh.
+    pub(super) async fn open_game(
+        &self,
+        room_id: &RoomRef,
+        uid: u128,
+        passcode: Option<&str>,
+    ) -> Result<Option<Game>, TeamStoreError> {
         if let Some(game) = self.get_game(room_id).await {
-            return game;
+            return Ok(Some(game));
+        }
+        let team = match room_id {
+            RoomRef::Slug(slug) => self.teams.get(slug).await?,
+            RoomRef::Id(_) => None,
+        };
+        let admitted = team.as_ref().is_none_or(|team| {
+            team.passcode.is_none()
+                || team.is_member(uid)
+                || passcode_matches(team.passcode.as_deref(), passcode)
+        });
+        if !admitted {
+            return Ok(None);
         }
-        self.game_states
-            .write()
+        Ok(Some(
+            self.game_states
+                .write()
+                .await
+                .get_or_create_game(room_id, team.as_ref())
+                .await,
+        ))
+    }
+
+    /// Creates a fresh room hosted by the player, bound to the given slug or
+    /// addressed by its random id otherwise, so it can't be guessed. Returns
+    /// `None` if the requested slug is taken.
+    pub(super) async fn create_game(
+        &self,
+        slug: Option<String>,
+        cards: Option<Vec<u64>>,
+        passcode: Option<String>,
+        host: u128,
+    ) -> Result<Option<RoomRef>, TeamStoreError> {
+        // Asked before taking the lock, every room would wait on the store
+        // otherwise
+        let team_exists = match &slug {
+            Some(slug) => self.teams.get(slug).await?.is_some(),
+            None => false,
+        };
+        let mut game_states = self.game_states.write().await;
+        // A room or a team may have taken the slug meanwhile
+        if team_exists || slug.as_deref().is_some_and(|slug| game_states.is_taken(slug)) {
+            return Ok(None);
+        }
+        let mut game = game_states.new_game();
+        game.settings.cards = cards.unwrap_or(game.settings.cards);
+        game.passcode = passcode;
+        game.host = Some(host);
+        game.members.insert(host);
+        let id = game_states.unused_id();
+        game_states
+            .games
+            .insert(id, Game(Arc::new(AsyncMutex::new(game))));
+        metrics::gauge!("rooms_active").set(game_states.games.len() as f64);
+        Ok(Some(match slug {
+            Some(slug) => {
+                game_states.slugs.insert(slug.clone(), id);
+                RoomRef::Slug(slug)
+            }
+            None => RoomRef::Id(id),
+        }))
+    }
+
+    pub(super) async fn default_settings(&self) -> RoomSettings {
+        self.game_states.read().await.defaults.settings()
+    }
+
+    /// Returns `false` if the slug is taken by a room or another team
+    pub(super) async fn create_team(&self, team: Team) -> Result<bool, TeamStoreError> {
+        // Reserved while the store creates the team, so no room can take the
+        // slug meanwhile
+        {
+            let mut game_states = self.game_states.write().await;
+            if game_states.is_taken(&team.slug) {
+                return Ok(false);
+            }
+            game_states.team_slugs.insert(team.slug.clone());
+        }
+        let created = self.teams.create(&team).await;
+        if !matches!(created, Ok(true)) {
+            self.game_states.write().await.team_slugs.remove(&team.slug);
+        }
+        created
+    }
+
+    pub(super) async fn add_team_member(
+        &self,
+        slug: &str,
+        uid: u128,
+        name: String,
+    ) -> Result<(), TeamStoreError> {
+        self.teams
+            .update(slug, |team| team.add_member(uid, name.clone()))
             .await
-            .get_or_create_game(room_id)
+    }
+
+    pub(super) async fn record_round(&self, slug: &str, round: Round) -> Result<(), TeamStoreError> {
+        let max_rounds = self.game_states.read().await.defaults.team_history;
+        self.teams
+            .update(slug, |team| {
+                team.record_round(round.clone(), max_rounds);
+                true
+            })
             .await
+            .inspect_err(|_| {
+                let mut unsaved = self.unsaved_rounds.lock().unwrap();
+                unsaved.push((slug.to_owned(), round.clone()));
+            })
+    }
+
+    pub(super) async fn save_team_settings(
+        &self,
+        slug: &str,
+        settings: RoomSettings,
+    ) -> Result<(), TeamStoreError> {
+        self.teams
+            .update(slug, |team| {
+                let changed = team.settings != settings;
+                team.settings = settings.clone();
+                changed
+            })
+            .await
+    }
+
+    pub(super) async fn get_team(&self, slug: &str) -> Result<Option<Team>, TeamStoreError> {
+        self.teams.get(slug).await
+    }
+
+    /// Teams the player is a member of
+    pub(super) async fn list_teams(&self, uid: u128) -> Result<Vec<TeamSummary>, TeamStoreError> {
+        let mut teams = self.teams.summaries(uid).await?;
+        teams.sort_unstable_by(|a, b| a.slug.cmp(&b.slug));
+        Ok(teams)
+    }
+
+    /// Removes the player from every room they're in or admitted to
+    pub async fn forget_player(&self, uid: u128) {
+        let games: Vec<_> = self.game_states.read().await.games.values().cloned().collect();
+        for game in games {
+            game.0.lock().await.forget_player(uid);
+        }
+    }
+
+    /// Generates a memorable slug that isn't bound to any room yet
+    pub(super) async fn generate_slug(&self) -> String {
+        self.game_states.read().await.unused_slug()
     }
 }
 
 #[derive(Debug, Default)]
 struct GameStates {
     games: HashMap<u64, Game>,
+    slugs: HashMap<String, u64>,
+    /// Slugs of the teams this instance created or is creating, no room can
+    /// take them
+    team_slugs: HashSet<String>,
+    defaults: RoomDefaults,
 }
 
 impl GameStates {
-    async fn get_game(&self, room_id: u64) -> Option<Game> {
-        self.games.get(&room_id).cloned()
+    fn is_taken(&self, slug: &str) -> bool {
+        self.slugs.contains_key(slug) || self.team_slugs.contains(slug)
+    }
+
+    fn new_game(&self) -> GameInner {
+        GameInner::new(&self.defaults)
     }
 
-    async fn get_or_create_game(&mut self, room_id: u64) -> Game {
-        self.games.entry(room_id).or_insert_with(Game::new).clone()
+    fn resolve(&self, room_id: &RoomRef) -> Option<u64> {
+        match room_id {
+            RoomRef::Id(id) => Some(*id),
+            RoomRef::Slug(slug) => self.slugs.get(slug).copied(),
+        }
+    }
+
+    async fn get_game(&self, room_id: &RoomRef) -> Option<Game> {
+        self.games.get(&self.resolve(room_id)?).cloned()
+    }
+
+    async fn get_or_create_game(&mut self, room_id: &RoomRef, team: Option<&Team>) -> Game {
+        let id = match self.resolve(room_id) {
+            Some(id) => id,
+            None => {
+                let id = self.unused_id();
+                if let RoomRef::Slug(slug) = room_id {
+                    self.slugs.insert(slug.clone(), id);
+                }
+                id
+            }
+        };
+        let game = match team {
+            Some(team) => GameInner::for_team(&self.defaults, team),
+            None => self.new_game(),
+        };
+        let game = self
+            .games
+            .entry(id)
+            .or_insert_with(|| Game(Arc::new(AsyncMutex::new(game))))
+            .clone();
+        metrics::gauge!("rooms_active").set(self.games.len() as f64);
+        game
+    }
+
+    fn unused_slug(&self) -> String {
+        loop {
+            let slug = gen_slug(random());
+            if !self.is_taken(&slug) {
+                return slug;
+            }
+        }
+    }
+
+    fn unused_id(&self) -> u64 {
+        loop {
+            let id = random();
+            if !self.games.contains_key(&id) {
+                return id;
+            }
+        }
     }
 
     // TODO: Support stale rooms removal
@@ -61,57 +389,253 @@ impl GameStates {
 #[derive(Debug, Default, Clone)]
 pub(super) struct Game(pub Arc<AsyncMutex<GameInner>>);
 
-impl Game {
-    fn new() -> Self {
-        Default::default()
-    }
-}
-
 #[derive(Debug)]
 pub(super) struct Player {
     card: Option<u64>,
-    receiver: mpsc::Sender<PlayerGameState>, // NOTE: this is a CRUNCH
+    receiver: watch::Sender<PlayerGameState>,
     name: String,
+    spectator: bool,
+    nudge: Option<Nudge>,
+    /// Kept when the player rejoins, the host role goes to whoever has been
+    /// in the room the longest
+    joined_at: Instant,
 }
 
 impl From<&Player> for PlayerState {
     fn from(player: &Player) -> PlayerState {
         PlayerState {
             card: player.card,
+            voted: player.card.is_some(),
             name: player.name.clone(),
+            spectator: player.spectator,
         }
     }
 }
 
+/// Round revealed in a team room: the team's slug and the round to add to
+/// its history
+pub(super) type TeamRound = (String, Round);
+
+/// What placing a card set off
+#[derive(Debug, Default)]
+pub(super) struct BetOutcome {
+    /// The card was the last one missing and the room revealed the cards
+    pub revealed: Option<TeamRound>,
+    /// The card started the timer of the round: the round and its length
+    pub timer: Option<(u64, Duration)>,
+}
+
+/// Which team the room belongs to
+#[derive(Debug)]
+struct TeamRoom {
+    slug: String,
+    members: HashSet<u128>,
+}
+
 #[derive(Debug)]
 pub(super) struct GameInner {
-    cards: Vec<u64>,
+    settings: RoomSettings,
     players: HashMap<u128, Player>,
     hidden: bool,
+    passcode: Option<String>,
+    members: HashSet<u128>,
+    max_players: usize,
+    team: Option<TeamRoom>,
+    /// Changes the settings. The creator of the room, or the first player to
+    /// join rooms created by visiting them. Handed to the longest present
+    /// player when the host leaves.
+    host: Option<u128>,
+    /// Bumped whenever the cards are hidden for a new round
+    round: u64,
+    /// When the timer of the round runs out
+    deadline: Option<Instant>,
+    last_nudge: Option<Instant>,
+    nudges_sent: u64,
 }
 
 impl Default for GameInner {
     fn default() -> Self {
+        Self::new(&RoomDefaults::default())
+    }
+}
+
+impl GameInner {
+    fn new(defaults: &RoomDefaults) -> Self {
         Self {
-            cards: vec![50, 100, 200, 300, 500, 800, 1300, 2100],
+            settings: defaults.settings(),
             players: Default::default(),
             hidden: true,
+            passcode: None,
+            members: Default::default(),
+            max_players: defaults.max_players,
+            team: None,
+            host: None,
+            round: 0,
+            deadline: None,
+            last_nudge: None,
+            nudges_sent: 0,
         }
     }
-}
 
-impl GameInner {
-    pub(super) async fn new_player(&mut self, uid: u128) -> mpsc::Receiver<PlayerGameState> {
-        let (tx, rx) = mpsc::channel(128);
+    /// Team members are admitted without the passcode, the team's creator
+    /// hosts the room
+    fn for_team(defaults: &RoomDefaults, team: &Team) -> Self {
+        let members: HashSet<u128> = team.members.iter().map(|member| member.uid).collect();
+        Self {
+            settings: team.settings.clone(),
+            host: team.members.first().map(|member| member.uid),
+            passcode: team.passcode.clone(),
+            members: members.clone(),
+            team: Some(TeamRoom {
+                slug: team.slug.clone(),
+                members,
+            }),
+            ..Self::new(defaults)
+        }
+    }
+
+    pub(super) fn is_host(&self, uid: u128) -> bool {
+        self.host == Some(uid)
+    }
+
+    /// Whether the player may reveal and hide the cards
+    pub(super) fn may_reveal(&self, uid: u128) -> bool {
+        match self.settings.reveal {
+            RevealPermission::Everyone => true,
+            RevealPermission::Host => self.is_host(uid),
+        }
+    }
+
+    /// Applies validated settings, cards that aren't in the deck anymore are
+    /// taken back. Returns the slug of the room's team to save them to.
+    pub(super) fn set_settings(&mut self, settings: RoomSettings) -> Option<String> {
+        for player in self.players.values_mut() {
+            if player.card.is_some_and(|card| !settings.cards.contains(&card)) {
+                player.card = None;
+            }
+            if !settings.allow_spectators {
+                player.spectator = false;
+            }
+        }
+        if settings.timer.is_none() {
+            self.deadline = None;
+        }
+        self.settings = settings;
+        self.send_update();
+        self.team_slug().map(str::to_owned)
+    }
+
+    /// Returns `false` if the room doesn't allow spectators
+    pub(super) fn set_spectator(&mut self, uid: u128, spectator: bool) -> bool {
+        if spectator && !self.settings.allow_spectators {
+            return false;
+        }
+        if let Some(player) = self.players.get_mut(&uid) {
+            player.spectator = spectator;
+            if spectator {
+                player.card = None;
+            }
+            self.send_update();
+        }
+        true
+    }
+
+    pub(super) fn team_slug(&self) -> Option<&str> {
+        self.team.as_ref().map(|team| team.slug.as_str())
+    }
+
+    /// Makes the player in the room a member of its team, returns the team's
+    /// slug and the player's name if they weren't one yet
+    pub(super) fn join_team(&mut self, uid: u128) -> Option<(String, String)> {
+        let player = self.players.get(&uid)?;
+        let team = self.team.as_mut()?;
+        team.members
+            .insert(uid)
+            .then(|| (team.slug.clone(), player.name.clone()))
+    }
+
+    /// Whether the room takes a passcode to get in
+    pub(super) fn is_private(&self) -> bool {
+        self.passcode.is_some()
+    }
+
+    /// Whether the player is allowed to take part in the game. Public rooms
+    /// admit everyone, private ones only those who've entered the passcode.
+    pub(super) fn is_member(&self, uid: u128) -> bool {
+        self.passcode.is_none() || self.members.contains(&uid)
+    }
+
+    /// Admits the player into the room if the passcode matches. Once admitted
+    /// the player doesn't have to provide the passcode again.
+    pub(super) fn admit(&mut self, uid: u128, passcode: Option<&str>) -> bool {
+        if self.is_member(uid) {
+            return true;
+        }
+        if passcode_matches(self.passcode.as_deref(), passcode) {
+            self.members.insert(uid);
+            return true;
+        }
+        false
+    }
+
+    /// Registers the player in the room under the given name or a random one,
+    /// returns `None` if the room is full. Rejoining players replace their
+    /// previous connection.
+    pub(super) fn new_player(
+        &mut self,
+        uid: u128,
+        name: Option<String>,
+    ) -> Option<watch::Receiver<PlayerGameState>> {
+        if !self.players.contains_key(&uid) && self.players.len() >= self.max_players {
+            return None;
+        }
+        let (tx, rx) = watch::channel(PlayerGameState::default());
+        let joined_at = self
+            .players
+            .get(&uid)
+            .map_or_else(Instant::now, |player| player.joined_at);
         let state = Player {
             card: None,
             receiver: tx,
-            name: gen_nickname(uid),
+            name: name.unwrap_or_else(|| gen_nickname(uid)),
+            spectator: false,
+            nudge: None,
+            joined_at,
         };
         self.players.insert(uid, state);
-        self.send_update().await;
+        self.host.get_or_insert(uid);
+        self.send_update();
+
+        Some(rx)
+    }
 
-        rx
+    /// Whether the player is connected to the room
+    pub(super) fn has_player(&self, uid: u128) -> bool {
+        self.players.contains_key(&uid)
+    }
+
+    /// Drops the player along with their admission into the room
+    pub(super) fn forget_player(&mut self, uid: u128) {
+        self.members.remove(&uid);
+        let removed = self.players.remove(&uid).is_some();
+        if self.hand_over_host(uid) || removed {
+            self.send_update();
+        }
+    }
+
+    /// Passes the host role on if the player leaving had it, returns whether
+    /// it did. Nobody hosts an empty room until someone joins.
+    fn hand_over_host(&mut self, leaving: u128) -> bool {
+        if self.host != Some(leaving) {
+            return false;
+        }
+        self.host = self
+            .players
+            .iter()
+            .filter(|&(&uid, _)| uid != leaving)
+            .min_by_key(|&(&uid, player)| (player.joined_at, uid))
+            .map(|(&uid, _)| uid);
+        true
     }
 
     // TODO: Support stale rooms removal
@@ -120,57 +644,171 @@ impl GameInner {
     // }
 
     #[allow(dead_code)]
-    pub(super) async fn add_new_card(&mut self, card: u64) {
-        self.cards.push(card);
-        self.cards.sort_unstable();
-        self.cards.dedup();
-        self.send_update().await;
+    pub(super) fn add_new_card(&mut self, card: u64) {
+        self.settings.cards.push(card);
+        self.settings.cards.sort_unstable();
+        self.settings.cards.dedup();
+        self.send_update();
     }
 
     #[allow(dead_code)]
-    pub(super) async fn remove_card(&mut self, card: u64) {
-        if let Some(pos) = self.cards.iter().position(|&v| v == card) {
-            self.cards.remove(pos);
-            self.send_update().await;
+    pub(super) fn remove_card(&mut self, card: u64) {
+        if let Some(pos) = self.settings.cards.iter().position(|&v| v == card) {
+            self.settings.cards.remove(pos);
+            self.send_update();
         }
     }
 
-    pub(super) async fn set_name(&mut self, uid: u128, name: String) {
+    pub(super) fn set_name(&mut self, uid: u128, name: String) {
         if let Some(player) = self.players.get_mut(&uid) {
             player.name = name;
-            self.send_update().await;
+            self.send_update();
         }
     }
 
-    pub(super) async fn place_bet(&mut self, uid: u128, card: Option<u64>) {
-        if let Some(player) = self.players.get_mut(&uid) {
-            player.card = card;
-            self.send_update().await;
+    /// Spectators can't vote. The first card of a round starts its timer, the
+    /// last one reveals the cards if the room reveals automatically.
+    pub(super) fn place_bet(&mut self, uid: u128, card: Option<u64>) -> BetOutcome {
+        let mut outcome = BetOutcome::default();
+        let Some(player) = self.players.get_mut(&uid).filter(|player| !player.spectator) else {
+            return outcome;
+        };
+        player.card = card;
+        if card.is_some() {
+            player.nudge = None;
+        }
+        let starts_timer = self.hidden && card.is_some() && self.deadline.is_none();
+        if let Some(timer) = self.settings.timer.filter(|_| starts_timer) {
+            let after = Duration::from_secs(timer.into());
+            self.deadline = Some(Instant::now() + after);
+            outcome.timer = Some((self.round, after));
+        }
+        if self.hidden && self.settings.auto_reveal && self.everyone_voted() {
+            outcome.revealed = self.reveal();
+        } else {
+            self.send_update();
+        }
+        outcome
+    }
+
+    fn everyone_voted(&self) -> bool {
+        let mut voters = self.players.values().filter(|player| !player.spectator).peekable();
+        voters.peek().is_some() && voters.all(|player| player.card.is_some())
+    }
+
+    /// Reminds everyone who hasn't voted yet, on behalf of the player. Returns
+    /// `false` if the room has been nudged too recently.
+    pub(super) fn nudge(&mut self, uid: u128) -> bool {
+        if self
+            .last_nudge
+            .is_some_and(|at| at.elapsed() < NUDGE_INTERVAL)
+        {
+            return false;
+        }
+        let Some(from) = self.players.get(&uid).map(|player| player.name.clone()) else {
+            return true;
+        };
+        if !self.hidden {
+            return true;
         }
+        self.last_nudge = Some(Instant::now());
+        for (&other_uid, player) in &mut self.players {
+            if other_uid == uid || player.spectator || player.card.is_some() {
+                continue;
+            }
+            self.nudges_sent += 1;
+            player.nudge = Some(Nudge {
+                id: self.nudges_sent,
+                from: from.clone(),
+            });
+        }
+        self.send_update();
+        true
     }
 
-    pub(super) async fn reveal(&mut self) {
-        self.hidden = false;
-        self.send_update().await;
+    /// Reveals the cards if the timer of the round has run out meanwhile
+    pub(super) fn timer_expired(&mut self, round: u64) -> Option<TeamRound> {
+        let expired = self
+            .deadline
+            .is_some_and(|deadline| deadline <= Instant::now());
+        if round != self.round || !self.hidden || !expired {
+            return None;
+        }
+        self.reveal()
+    }
+
+    /// Returns the round to add to the team's history, if the room has a team
+    /// and anyone has voted
+    pub(super) fn reveal(&mut self) -> Option<TeamRound> {
+        let was_hidden = mem::replace(&mut self.hidden, false);
+        self.deadline = None;
+        self.send_update();
+        let slug = self.team_slug()?.to_owned();
+        if !was_hidden || self.players.values().all(|player| player.card.is_none()) {
+            return None;
+        }
+        let revealed_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let votes = match self.anonymous_cards() {
+            Some(cards) => cards
+                .into_iter()
+                .map(|card| Vote {
+                    name: None,
+                    card: Some(card),
+                })
+                .collect(),
+            None => self
+                .players
+                .values()
+                .map(|player| Vote {
+                    name: Some(player.name.clone()),
+                    card: player.card,
+                })
+                .collect(),
+        };
+        Some((slug, Round { revealed_at, votes }))
+    }
+
+    /// The revealed cards apart from who played them, if the room is anonymous
+    fn anonymous_cards(&self) -> Option<Vec<u64>> {
+        if self.hidden || !self.settings.anonymous {
+            return None;
+        }
+        let mut cards: Vec<_> = self.players.values().filter_map(|player| player.card).collect();
+        cards.sort_unstable();
+        Some(cards)
     }
 
-    pub(super) async fn hide(&mut self) {
+    pub(super) fn hide(&mut self) {
         self.hidden = true;
+        self.round += 1;
+        self.deadline = None;
         for state in self.players.values_mut() {
             state.card = None;
+            state.nudge = None;
         }
-        self.send_update().await;
+        self.send_update();
     }
 
-    pub(super) async fn send_update(&mut self) {
+    pub(super) fn send_update(&mut self) {
+        let started = Instant::now();
+        let time_left = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(started).as_secs_f64().ceil() as u32);
+        let anonymous_cards = self.anonymous_cards();
         let mut disconnected = vec![];
         loop {
             for (&self_uid, self_state) in &self.players {
                 let mut player_game_state = PlayerGameState {
-                    cards: self.cards.clone(),
+                    settings: self.settings.clone(),
                     players: vec![],
                     self_state: self_state.into(),
                     hidden: self.hidden,
+                    is_host: self.is_host(self_uid),
+                    time_left,
+                    anonymous_cards: anonymous_cards.clone(),
+                    nudge: self_state.nudge.clone(),
                 };
 
                 for (&other_uid, other_state) in &self.players {
@@ -178,13 +816,15 @@ impl GameInner {
                         continue;
                     }
                     let mut other_state: PlayerState = other_state.into();
-                    if self.hidden {
-                        other_state.card = other_state.card.map(|_| 0);
+                    if self.hidden || anonymous_cards.is_some() {
+                        other_state.card = None;
                     }
                     player_game_state.players.push(other_state);
                 }
 
-                if let Err(e) = self_state.receiver.send(player_game_state).await {
+                // Slow consumers only ever see the latest state, so the room
+                // never waits on them
+                if let Err(e) = self_state.receiver.send(player_game_state) {
                     tracing::debug!("Failed to send info to player {self_uid} due to {e:?}");
                     disconnected.push(self_uid);
                 }
@@ -192,10 +832,192 @@ impl GameInner {
             if disconnected.is_empty() {
                 break;
             }
+            // Sent again, the new host has to learn about the role
             for &uid in &disconnected {
                 self.players.remove(&uid);
+                self.hand_over_host(uid);
             }
             disconnected.clear();
         }
+        metrics::histogram!("send_update_duration_seconds").record(started.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passcode_matches_only_equal_passcodes() {
+        assert!(passcode_matches(Some("secret"), Some("secret")));
+        assert!(!passcode_matches(Some("secret"), Some("secreT")));
+        assert!(!passcode_matches(Some("secret"), Some("secret2")));
+        assert!(!passcode_matches(Some("secret"), None));
+        assert!(!passcode_matches(None, Some("secret")));
+        assert!(!passcode_matches(None, None));
+    }
+
+    #[test]
+    fn admit_remembers_players_with_the_passcode() {
+        let mut game = GameInner {
+            passcode: Some("secret".to_owned()),
+            ..Default::default()
+        };
+        assert!(!game.admit(1, None));
+        assert!(!game.admit(1, Some("wrong")));
+        assert!(game.admit(1, Some("secret")));
+        assert!(game.admit(1, None));
+        assert!(!game.admit(2, None));
+    }
+
+    #[tokio::test]
+    async fn takeover_and_removal_are_told_apart() {
+        let mut game = GameInner::default();
+        let mut first = game.new_player(1, None).unwrap();
+        let mut second = game.new_player(1, None).unwrap();
+        // Pending updates are received before the closing
+        while first.changed().await.is_ok() {}
+        assert!(game.has_player(1));
+
+        game.forget_player(1);
+        while second.changed().await.is_ok() {}
+        assert!(!game.has_player(1));
+    }
+
+    #[tokio::test]
+    async fn shutdown_saves_team_rooms() {
+        let state = ServerState::new(RoomDefaults::default(), TeamStore::default());
+        let team = Team {
+            slug: "team".to_owned(),
+            members: Vec::new(),
+            settings: Default::default(),
+            passcode: None,
+            history: Vec::new(),
+        };
+        assert!(state.create_team(team).await.unwrap());
+        let room = RoomRef::Slug("team".to_owned());
+        let game = state.open_game(&room, 1, None).await.unwrap().unwrap();
+        let settings = RoomSettings {
+            title: Some("Saved".to_owned()),
+            ..Default::default()
+        };
+        {
+            let mut game = game.0.lock().await;
+            game.settings = settings.clone();
+            game.new_player(1, None);
+            game.join_team(1);
+        }
+
+        state.shutdown().await;
+        let team = state.get_team("team").await.unwrap().unwrap();
+        assert_eq!(team.settings, settings);
+        assert!(team.is_member(1));
+    }
+
+    #[tokio::test]
+    async fn host_role_is_handed_over() {
+        let mut game = GameInner::default();
+        let host = game.new_player(1, None).unwrap();
+        let mut second = game.new_player(2, None).unwrap();
+        let mut third = game.new_player(3, None).unwrap();
+        assert!(game.is_host(1));
+
+        // Disconnected players are dropped on the next update
+        drop(host);
+        game.send_update();
+        assert!(game.is_host(2));
+        assert!(second.borrow_and_update().is_host);
+        assert!(!third.borrow_and_update().is_host);
+
+        game.forget_player(2);
+        assert!(game.is_host(3));
+        assert!(third.borrow_and_update().is_host);
+
+        game.forget_player(3);
+        assert_eq!(game.host, None);
+        game.new_player(4, None);
+        assert!(game.is_host(4));
+    }
+
+    fn team(slug: &str, member: u128) -> Team {
+        let mut team = Team {
+            slug: slug.to_owned(),
+            members: Vec::new(),
+            settings: Default::default(),
+            passcode: None,
+            history: Vec::new(),
+        };
+        team.add_member(member, "name".to_owned());
+        team
+    }
+
+    #[tokio::test]
+    async fn rooms_and_teams_take_distinct_slugs() {
+        let state = ServerState::default();
+        assert!(state.create_team(team("team", 1)).await.unwrap());
+        assert_eq!(state.create_game(Some("team".to_owned()), None, None, 1).await.unwrap(), None);
+
+        let room = state.create_game(Some("room".to_owned()), None, None, 1).await.unwrap();
+        assert_eq!(room, Some(RoomRef::Slug("room".to_owned())));
+        assert!(!state.create_team(team("room", 1)).await.unwrap());
+        assert_eq!(state.create_game(Some("room".to_owned()), None, None, 2).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn visited_slugs_are_taken() {
+        let state = ServerState::default();
+        let room = RoomRef::Slug("visited".to_owned());
+        state.open_game(&room, 1, None).await.unwrap().unwrap();
+        assert_eq!(state.create_game(Some("visited".to_owned()), None, None, 2).await.unwrap(), None);
+        assert_ne!(state.generate_slug().await, "visited");
+    }
+
+    #[tokio::test]
+    async fn unnamed_rooms_get_random_ids() {
+        let state = ServerState::default();
+        let first = state.create_game(None, None, None, 1).await.unwrap().unwrap();
+        let second = state.create_game(None, None, None, 1).await.unwrap().unwrap();
+        assert!(matches!(first, RoomRef::Id(_)));
+        assert_ne!(first, second);
+        assert!(state.get_game(&first).await.unwrap().0.lock().await.is_host(1));
+    }
+
+    #[tokio::test]
+    async fn lists_teams_of_the_member() {
+        let dir = std::env::temp_dir().join(format!("scrum-poker-teams-{:x}", random::<u64>()));
+        for store in [TeamStore::default(), TeamStore::file(&dir).await.unwrap()] {
+            let state = ServerState::new(RoomDefaults::default(), store);
+            state.create_team(team("b", 1)).await.unwrap();
+            state.create_team(team("a", 1)).await.unwrap();
+            state.create_team(team("c", 2)).await.unwrap();
+            let round = Round {
+                revealed_at: 0,
+                votes: Vec::new(),
+            };
+            state.record_round("a", round).await.unwrap();
+            // Teams that can't be read don't take the others down
+            std::fs::write(dir.join("broken.json"), b"{").unwrap();
+
+            let teams = state.list_teams(1).await.unwrap();
+            let listed: Vec<_> = teams
+                .iter()
+                .map(|team| (team.slug.as_str(), team.members, team.rounds))
+                .collect();
+            assert_eq!(listed, [("a", 1, 1), ("b", 1, 0)]);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_players_in_the_room_join_its_team() {
+        let mut game = GameInner::for_team(&RoomDefaults::default(), &team("team", 1));
+        assert_eq!(game.join_team(2), None);
+        game.new_player(2, Some("Bob".to_owned()));
+        assert_eq!(game.join_team(2), Some(("team".to_owned(), "Bob".to_owned())));
+        assert_eq!(game.join_team(2), None);
+
+        let mut room = GameInner::default();
+        room.new_player(2, None);
+        assert_eq!(room.join_team(2), None);
     }
 }
@@ -1,52 +1,188 @@
-use super::api::{PlayerGameState, PlayerState, check_username, hide, place_bet, reveal, set_name};
+use super::api::{
+    PlayerGameState, PlayerState, RevealPermission, RoomRef, RoomSettings, Round, ServerError,
+    check_username, get_team_details, hide, join_team, nudge_players, parse_deck, place_bet,
+    reveal, set_name, set_spectator, update_room_settings,
+};
 use crate::{
+    components::toast::{Toasts, use_toasts},
     error_template::{AppError, ErrorTemplate},
     if_backend, if_frontend,
 };
-use leptos::{either::Either, leptos_dom::logging::console_log, prelude::*};
+use leptos::{
+    either::{Either, EitherOf3},
+    leptos_dom::logging::console_log,
+    prelude::*,
+};
 use leptos_router::{hooks::use_params, params::Params};
-use std::{cmp::Reverse, iter, ops::Deref};
+use std::{cmp::Reverse, iter, mem, ops::Deref};
+
+/// Why the room is reconnecting to the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "hydrate"), allow(dead_code))]
+enum Reconnecting {
+    /// The server announced it's going down
+    ServerRestarting,
+    /// The connection broke without notice
+    ConnectionLost,
+}
 
 fn game_state_updates(
-    room_id: u64,
-) -> impl Read<Value: Deref<Target = PlayerGameState>> + With<Value = PlayerGameState> + Copy {
-    let (state, set_state) = signal(PlayerGameState::default());
+    room_id: RoomRef,
+) -> (
+    RwSignal<PlayerGameState>,
+    ReadSignal<Option<AppError>>,
+    ReadSignal<Option<Reconnecting>>,
+) {
+    let state = RwSignal::new(PlayerGameState::default());
+    let (error, set_error) = signal(None);
+    let (reconnecting, set_reconnecting) = signal(None);
 
     if_frontend! {
-        use super::api::{UserStreamRequest, subscribe_to_room};
-        use futures::{StreamExt, stream};
+        use super::api::{RoomEvent, UserStreamRequest, subscribe_to_room};
+        use futures::{StreamExt, channel::oneshot, stream};
         use leptos::task::spawn_local;
+        use std::time::Duration;
+        /// How many times in a row the client tries to reconnect before giving up
+        const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+        async fn sleep(duration: Duration) {
+            let (tx, rx) = oneshot::channel();
+            set_timeout(move || { let _ = tx.send(()); }, duration);
+            let _ = rx.await;
+        }
+
+        /// Error page to show once the room can't be played in anymore
+        fn page_error(e: ServerError) -> AppError {
+            match e {
+                ServerError::RoomNotFound => AppError::RoomClosed,
+                ServerError::Unauthorized => AppError::SessionExpired,
+                ServerError::Forbidden | ServerError::HostOnly | ServerError::WrongPasscode => {
+                    AppError::Forbidden
+                }
+                ServerError::RoomFull => AppError::RoomFull,
+                ServerError::RoomExists
+                | ServerError::InvalidName(_)
+                | ServerError::InvalidInput { .. }
+                | ServerError::RateLimited
+                | ServerError::Internal
+                | ServerError::ServerFn(_) => AppError::ServerUnavailable,
+            }
+        }
 
         spawn_local(async move {
-            let states = subscribe_to_room(
-                stream::once(async move { Ok(UserStreamRequest::SetRoom(room_id)) }).into(),
-            )
-            .await;
-            let mut states = match states {
-                Ok(s) => s,
-                Err(e) => {
-                    console_log(&format!("Error subscribing: {e:?}"));
+            let mut attempt = 0;
+            // Anything but an announced restart is a lost connection
+            let mut cause = Reconnecting::ConnectionLost;
+            loop {
+                if attempt > MAX_RECONNECT_ATTEMPTS {
+                    set_reconnecting.set(None);
+                    set_error.set(Some(AppError::ServerUnavailable));
                     return;
                 }
-            };
+                if attempt > 0 {
+                    set_reconnecting.set(Some(cause));
+                    sleep(Duration::from_millis(500 << attempt.min(4))).await;
+                }
+                attempt += 1;
+                cause = Reconnecting::ConnectionLost;
 
-            while let Some(msg) = states.next().await {
-                match msg {
-                    Ok(state) => {
-                        set_state.set(state);
-                    }
+                let request = UserStreamRequest::SetRoom {
+                    room: room_id.clone(),
+                    // Entered through `join_room` and kept in the session
+                    passcode: None,
+                };
+                let states = subscribe_to_room(
+                    stream::once(async move { Ok(request) }).into(),
+                )
+                .await;
+                let mut states = match states {
+                    Ok(s) => s,
                     Err(e) => {
-                        console_log(&format!("Error receiving msg: {e:?}"));
-                        break;
+                        console_log(&format!("Error subscribing: {e:?}"));
+                        continue;
+                    }
+                };
+
+                while let Some(msg) = states.next().await {
+                    match msg {
+                        Ok(RoomEvent::State(new_state)) => {
+                            attempt = 0;
+                            set_reconnecting.set(None);
+                            state.set(*new_state);
+                        }
+                        Ok(RoomEvent::ServerRestarting) => {
+                            console_log("Server is restarting, reconnecting");
+                            cause = Reconnecting::ServerRestarting;
+                            set_reconnecting.set(Some(cause));
+                            break;
+                        }
+                        Ok(RoomEvent::JoinedElsewhere) => {
+                            set_reconnecting.set(None);
+                            set_error.set(Some(AppError::Kicked));
+                            return;
+                        }
+                        Ok(RoomEvent::Removed) => {
+                            set_reconnecting.set(None);
+                            set_error.set(Some(AppError::Removed));
+                            return;
+                        }
+                        // The connection broke, not the room
+                        Err(ServerError::ServerFn(e)) => {
+                            console_log(&format!("Connection error, reconnecting: {e}"));
+                            break;
+                        }
+                        Err(e) => {
+                            console_log(&format!("Error receiving msg: {e:?}"));
+                            set_reconnecting.set(None);
+                            set_error.set(Some(page_error(e)));
+                            return;
+                        }
                     }
                 }
             }
         });
     }
     if_backend! {
-        let _ = (room_id, set_state);
+        let _ = (room_id, set_error, set_reconnecting);
+    }
+    (state, error, reconnecting)
+}
+
+/// Local copy of the game state the room's controls change optimistically,
+/// before the server confirms the change
+#[derive(Clone, Copy)]
+struct LocalState {
+    state: RwSignal<PlayerGameState>,
+    toasts: Toasts,
+}
+
+impl LocalState {
+    /// Applies `value` to the `field` right away and reverts it if the
+    /// request fails, unless the server has sent a fresher state meanwhile
+    async fn optimistic<T: Clone + PartialEq>(
+        self,
+        field: fn(&mut PlayerGameState) -> &mut T,
+        value: T,
+        request: impl Future<Output = Result<(), ServerError>>,
+    ) {
+        let mut previous = None;
+        self.state
+            .update(|state| previous = Some(mem::replace(field(state), value.clone())));
+        if let Err(e) = request.await {
+            self.state.update(|state| {
+                let current = field(state);
+                if *current == value {
+                    *current = previous.take().expect("Previous value to be saved");
+                }
+            });
+            self.report(e);
+        }
+    }
+
+    fn report(self, e: ServerError) {
+        console_log(&format!("Received error response {e:?}"));
+        self.toasts.error(e.to_string());
     }
-    state
 }
 
 #[component]
@@ -59,15 +195,17 @@ fn CardThick() -> impl IntoView {
 }
 
 #[component]
-fn NameChange(current_name: String, room_id: u64) -> impl IntoView {
+fn NameChange(current_name: String, room_id: RoomRef) -> impl IntoView {
     let (new_name, set_new_name) = signal(current_name);
+    let local = expect_context::<LocalState>();
     let set_name = Action::new(move |name: &String| {
         let name = name.clone();
-        async move {
-            if let Err(e) = set_name(room_id, name.clone()).await {
-                console_log(&format!("Received error response {e:?}"));
-            }
-        }
+        let room_id = room_id.clone();
+        local.optimistic(
+            |state| &mut state.self_state.name,
+            name.clone(),
+            set_name(room_id, name),
+        )
     });
     let nameError = Memo::new(move |_| new_name.with(|s| check_username(s)));
     let on_submit = move |ev: leptos::ev::SubmitEvent| {
@@ -98,8 +236,8 @@ fn NameChange(current_name: String, room_id: u64) -> impl IntoView {
                 <input type="text" class="grow" prop:value=new_name on:input=on_input />
                 { move ||
                     match nameError.get() {
-                        Ok(_) => Either::Left(view!{}),
-                        Err(e) => Either::Right(view! {
+                        Ok(_) => None,
+                        Err(e) => Some(view! {
                             <div class="label">
                                 <span class="label-text-alt text-error">{ e }</span>
                             </div>
@@ -123,14 +261,18 @@ fn CardChange<
 >(
     cards: CardsSignal,
     self_card: SelfCardSignal,
-    creds: u64,
+    creds: RoomRef,
 ) -> impl IntoView {
     let room_id = creds;
+    let local = expect_context::<LocalState>();
 
-    let place_bet = Action::new(move |&card: &Option<u64>| async move {
-        if let Err(e) = place_bet(room_id, card).await {
-            console_log(&format!("Received error response {e:?}"));
-        }
+    let place_bet = Action::new(move |&card: &Option<u64>| {
+        let room_id = room_id.clone();
+        local.optimistic(
+            |state| &mut state.self_state.card,
+            card,
+            place_bet(room_id, card),
+        )
     });
     view! {
         <div>
@@ -156,33 +298,45 @@ fn CardChange<
 fn HideReveal<
     HiddenSignal: Get<Value = bool> + Copy + Send + Sync + 'static,
     AvgSignal: Get<Value = u64> + Copy + Send + Sync + 'static,
+    AllowedSignal: Get<Value = bool> + Copy + Send + Sync + 'static,
 >(
     hidden: HiddenSignal,
     avg: AvgSignal,
-    room_id: u64,
+    /// Whether the player may reveal and hide the cards
+    allowed: AllowedSignal,
+    room_id: RoomRef,
 ) -> impl IntoView {
-    let reveal = Action::new(move |_: &()| async move {
-        if let Err(e) = reveal(room_id).await {
-            console_log(&format!("Received error response {e:?}"));
+    let local = expect_context::<LocalState>();
+    // Revealing can't be done optimistically, the cards of others are only
+    // known once the server sends them
+    let reveal = Action::new({
+        let room_id = room_id.clone();
+        move |_: &()| {
+            let room_id = room_id.clone();
+            async move {
+                if let Err(e) = reveal(room_id).await {
+                    local.report(e);
+                }
+            }
         }
     });
 
-    let hide = Action::new(move |_: &()| async move {
-        if let Err(e) = hide(room_id).await {
-            console_log(&format!("Received error response {e:?}"));
-        }
+    let hide = Action::new(move |_: &()| {
+        let room_id = room_id.clone();
+        local.optimistic(|state| &mut state.hidden, true, hide(room_id))
     });
+    let disabled = move || reveal.pending().get() || hide.pending().get() || !allowed.get();
 
     view! {
         <div>
         { move || {
             if hidden.get() {
                 Either::Left(view! {
-                    <button on:click=move |_| { reveal.dispatch(()); } class="btn">"Reveal"</button>
+                    <button on:click=move |_| { reveal.dispatch(()); } class="btn" disabled=disabled>"Reveal"</button>
                 })
             } else {
                 Either::Right(view! {
-                    <button on:click=move |_| { hide.dispatch(()); } class="btn">
+                    <button on:click=move |_| { hide.dispatch(()); } class="btn" disabled=disabled>
                         "Average is " { convert_to_double(avg.get()) }
                     </button>
                 })
@@ -209,17 +363,15 @@ fn GameStateTable<
             <tbody>
             { move || {
                 let state = game_state.read();
-                let render_player = |PlayerState { card, name }, is_self: bool| view! {
+                let render_player = |PlayerState { card, voted, name, spectator }, is_self: bool| view! {
                     <tr class=if is_self { "bg-base-300" } else { "hover:bg-base-200" }>
                         <td>{ name }</td>
                         <td>
                         { match card {
-                            Some(v) => Either::Left(if state.hidden && !is_self {
-                                Either::Left(CardThick())
-                            } else {
-                                Either::Right(convert_to_double(v))
-                            }),
-                            None => Either::Right(""),
+                            Some(v) => EitherOf3::A(convert_to_double(v)),
+                            None if voted => EitherOf3::B(CardThick()),
+                            None if spectator => EitherOf3::C("watching"),
+                            None => EitherOf3::C(""),
                         }}
                         </td>
                     </tr>
@@ -231,7 +383,8 @@ fn GameStateTable<
                         .map(|v| (v.clone(), false))
                         .chain(iter::once((state.self_state.clone(), true)))
                         .collect::<Vec<_>>();
-                if !state.hidden {
+                // Cards of others stay face down in anonymous rooms
+                if !state.hidden && state.anonymous_cards.is_none() {
                     players.sort_unstable_by_key(|player| Reverse(player.0.card));
                 }
                 players
@@ -244,9 +397,406 @@ fn GameStateTable<
     }
 }
 
+/// How many players have voted, with a button reminding the others
+#[component]
+fn VoteProgress(game_state: RwSignal<PlayerGameState>, room_id: RoomRef) -> impl IntoView {
+    let local = expect_context::<LocalState>();
+    let progress = Memo::new(move |_| {
+        game_state.with(|state| {
+            let voters = state.players.iter().filter(|player| !player.spectator);
+            let voted = voters.clone().filter(|player| player.voted).count();
+            let total = voters.count();
+            if state.self_state.spectator {
+                (voted, total)
+            } else {
+                (voted + state.self_state.card.is_some() as usize, total + 1)
+            }
+        })
+    });
+    let nudge = Action::new(move |_: &()| {
+        let room_id = room_id.clone();
+        async move {
+            match nudge_players(room_id).await {
+                Ok(()) => local.toasts.info("Reminded everyone who hasn't voted"),
+                Err(e) => local.report(e),
+            }
+        }
+    });
+
+    view! {
+        { move || {
+            let (voted, total) = progress.get();
+            (total > 0).then(|| view! {
+                <span class="text-sm">{ format!("{voted}/{total} voted") }</span>
+                { (voted < total).then(|| view! {
+                    <button
+                        class="btn btn-ghost btn-sm"
+                        disabled=move || nudge.pending().get()
+                        on:click=move |_| { nudge.dispatch(()); }
+                    >
+                        "Nudge"
+                    </button>
+                })}
+            })
+        }}
+    }
+}
+
+/// Seconds left on the round's timer, counted down locally between the
+/// updates from the server
+#[component]
+fn Countdown<TimeLeftSignal: Get<Value = Option<u32>> + Copy + Send + Sync + 'static>(
+    time_left: TimeLeftSignal,
+) -> impl IntoView {
+    let (left, set_left) = signal(None::<u32>);
+    Effect::new(move || set_left.set(time_left.get()));
+    if_frontend! {
+        use std::time::Duration;
+
+        let tick = move || set_left.update(|left| {
+            if let Some(left) = left {
+                *left = left.saturating_sub(1);
+            }
+        });
+        if let Ok(handle) = set_interval_with_handle(tick, Duration::from_secs(1)) {
+            on_cleanup(move || handle.clear());
+        }
+    }
+
+    view! {
+        { move || left.get().map(|left| view! {
+            <span class="badge badge-outline badge-lg">{ format!("{}:{:02}", left / 60, left % 60) }</span>
+        })}
+    }
+}
+
+#[component]
+fn SpectatorToggle<SpectatorSignal: Get<Value = bool> + Copy + Send + Sync + 'static>(
+    spectator: SpectatorSignal,
+    room_id: RoomRef,
+) -> impl IntoView {
+    let local = expect_context::<LocalState>();
+    let set_spectator = Action::new(move |&spectator: &bool| {
+        let room_id = room_id.clone();
+        local.optimistic(
+            |state| &mut state.self_state.spectator,
+            spectator,
+            set_spectator(room_id, spectator),
+        )
+    });
+
+    view! {
+        <button class="btn btn-ghost" on:click=move |_| { set_spectator.dispatch(!spectator.get()); }>
+            { move || if spectator.get() { "Join the voting" } else { "Just watch" } }
+        </button>
+    }
+}
+
+/// Empty for no timer
+fn parse_timer(s: &str) -> Result<Option<u32>, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    match s.parse() {
+        Ok(timer) if RoomSettings::TIMER_RANGE.contains(&timer) => Ok(Some(timer)),
+        _ => Err(format!(
+            "Has to be {} to {} seconds",
+            RoomSettings::TIMER_RANGE.start(),
+            RoomSettings::TIMER_RANGE.end()
+        )),
+    }
+}
+
+/// Lets the host change the room's settings, starting from the given ones
+#[component]
+fn SettingsModal(room_id: RoomRef, settings: RoomSettings, open: RwSignal<bool>) -> impl IntoView {
+    let local = expect_context::<LocalState>();
+    let deck_text = settings
+        .cards
+        .iter()
+        .map(|&card| convert_to_double(card))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let (title, set_title) = signal(settings.title.unwrap_or_default());
+    let (deck, set_deck) = signal(deck_text);
+    let (reveal, set_reveal) = signal(settings.reveal);
+    let (auto_reveal, set_auto_reveal) = signal(settings.auto_reveal);
+    let (timer, set_timer) = signal(
+        settings
+            .timer
+            .map(|timer| timer.to_string())
+            .unwrap_or_default(),
+    );
+    let (allow_spectators, set_allow_spectators) = signal(settings.allow_spectators);
+    let (anonymous, set_anonymous) = signal(settings.anonymous);
+    let deck_error = Memo::new(move |_| deck.with(|deck| parse_deck(deck)).err());
+    let timer_error = Memo::new(move |_| timer.with(|timer| parse_timer(timer)).err());
+
+    let save = Action::new(move |settings: &RoomSettings| {
+        let room_id = room_id.clone();
+        local.optimistic(
+            |state| &mut state.settings,
+            settings.clone(),
+            update_room_settings(room_id, settings.clone()),
+        )
+    });
+    let on_submit = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        let (Ok(cards), Ok(timer)) = (
+            deck.with(|deck| parse_deck(deck)),
+            timer.with(|timer| parse_timer(timer)),
+        ) else {
+            return;
+        };
+        let title = title.get();
+        save.dispatch(RoomSettings {
+            title: (!title.trim().is_empty()).then(|| title.trim().to_owned()),
+            cards,
+            reveal: reveal.get(),
+            auto_reveal: auto_reveal.get(),
+            timer,
+            allow_spectators: allow_spectators.get(),
+            anonymous: anonymous.get(),
+        });
+        open.set(false);
+    };
+    let error_label = |error: Option<String>| {
+        error.map(|e| {
+            view! {
+                <span class="label-text-alt text-error">{ e }</span>
+            }
+        })
+    };
+
+    view! {
+        <dialog class="modal modal-open">
+            <div class="modal-box">
+                <h3 class="font-bold text-lg">"Room settings"</h3>
+                <form class="flex flex-col gap-2 mt-4" on:submit=on_submit>
+                    <input
+                        type="text"
+                        placeholder="Title (optional)"
+                        maxlength=RoomSettings::MAX_TITLE_LEN
+                        class="input input-bordered w-full"
+                        prop:value=title
+                        on:input=move |ev| set_title(event_target_value(&ev))
+                    />
+                    <input
+                        type="text"
+                        placeholder="Deck, e.g. 0.5, 1, 2, 3, 5"
+                        class="input input-bordered w-full"
+                        class:input-error=move || deck_error.read().is_some()
+                        prop:value=deck
+                        on:input=move |ev| set_deck(event_target_value(&ev))
+                    />
+                    { move || error_label(deck_error.get()) }
+                    <select
+                        class="select select-bordered w-full"
+                        on:change=move |ev| set_reveal(match event_target_value(&ev).as_str() {
+                            "host" => RevealPermission::Host,
+                            _ => RevealPermission::Everyone,
+                        })
+                    >
+                        <option value="everyone" selected=move || reveal.get() == RevealPermission::Everyone>
+                            "Everyone can reveal"
+                        </option>
+                        <option value="host" selected=move || reveal.get() == RevealPermission::Host>
+                            "Only the host can reveal"
+                        </option>
+                    </select>
+                    <label class="label cursor-pointer">
+                        <span class="label-text">"Reveal once everyone has voted"</span>
+                        <input
+                            type="checkbox"
+                            class="toggle"
+                            prop:checked=auto_reveal
+                            on:change=move |ev| set_auto_reveal(event_target_checked(&ev))
+                        />
+                    </label>
+                    <input
+                        type="text"
+                        inputmode="numeric"
+                        placeholder="Seconds to vote after the first card (optional)"
+                        class="input input-bordered w-full"
+                        class:input-error=move || timer_error.read().is_some()
+                        prop:value=timer
+                        on:input=move |ev| set_timer(event_target_value(&ev))
+                    />
+                    { move || error_label(timer_error.get()) }
+                    <label class="label cursor-pointer">
+                        <span class="label-text">"Allow spectators"</span>
+                        <input
+                            type="checkbox"
+                            class="toggle"
+                            prop:checked=allow_spectators
+                            on:change=move |ev| set_allow_spectators(event_target_checked(&ev))
+                        />
+                    </label>
+                    <label class="label cursor-pointer">
+                        <span class="label-text">"Reveal the cards without who played them"</span>
+                        <input
+                            type="checkbox"
+                            class="toggle"
+                            prop:checked=anonymous
+                            on:change=move |ev| set_anonymous(event_target_checked(&ev))
+                        />
+                    </label>
+                    <div class="modal-action">
+                        <button type="button" class="btn btn-ghost" on:click=move |_| open.set(false)>"Cancel"</button>
+                        <button
+                            type="submit"
+                            class="btn"
+                            disabled=move || deck_error.read().is_some() || timer_error.read().is_some()
+                        >
+                            "Save"
+                        </button>
+                    </div>
+                </form>
+            </div>
+        </dialog>
+    }
+}
+
+/// Watching instead of voting and, for the host, the room's settings
+#[component]
+fn RoomControls(game_state: RwSignal<PlayerGameState>, room_id: RoomRef) -> impl IntoView {
+    let is_host = Memo::new(move |_| game_state.with(|state| state.is_host));
+    let spectator = Memo::new(move |_| game_state.with(|state| state.self_state.spectator));
+    let allow_spectators =
+        Memo::new(move |_| game_state.with(|state| state.settings.allow_spectators));
+    let settings_open = RwSignal::new(false);
+    let settings_room_id = room_id.clone();
+
+    view! {
+        <div class="mt-2 flex gap-2">
+            <Show when=move || allow_spectators.get() || spectator.get()>
+                <SpectatorToggle spectator=spectator room_id=room_id.clone() />
+            </Show>
+            <Show when=move || is_host.get()>
+                <button class="btn btn-ghost" on:click=move |_| settings_open.set(true)>"Settings"</button>
+            </Show>
+        </div>
+        { move || settings_open.get().then(|| view! {
+            <SettingsModal
+                room_id=settings_room_id.clone()
+                settings=game_state.with_untracked(|state| state.settings.clone())
+                open=settings_open
+            />
+        })}
+    }
+    .into_any()
+}
+
+/// How many players have played each card, for anonymous rooms
+#[component]
+fn Distribution(cards: Vec<u64>) -> impl IntoView {
+    let mut counts: Vec<(u64, usize)> = vec![];
+    for card in cards {
+        match counts.last_mut() {
+            Some((last, count)) if *last == card => *count += 1,
+            _ => counts.push((card, 1)),
+        }
+    }
+
+    view! {
+        <div class="flex flex-wrap gap-2">
+            { counts.into_iter().map(|(card, count)| view! {
+                <span class="badge badge-lg">{ format!("{} \u{d7} {count}", convert_to_double(card)) }</span>
+            }).collect_view() }
+        </div>
+    }
+}
+
+fn format_round(round: &Round) -> String {
+    let votes: Vec<_> = round
+        .votes
+        .iter()
+        .filter_map(|vote| {
+            let card = convert_to_double(vote.card?);
+            Some(match &vote.name {
+                Some(name) => format!("{name} {card}"),
+                None => card,
+            })
+        })
+        .collect();
+    votes.join(", ")
+}
+
+/// Members and past rounds of a team room, nothing for other rooms
+#[component]
+fn TeamPanel<HiddenSignal: Get<Value = bool> + Copy + Send + Sync + 'static>(
+    room_id: RoomRef,
+    hidden: HiddenSignal,
+) -> impl IntoView {
+    // Fetched in the browser only, the player may not be admitted yet while
+    // rendering on the server. Fetched again once a round gets revealed.
+    let details = LocalResource::new({
+        let room_id = room_id.clone();
+        move || {
+            let _ = hidden.get();
+            get_team_details(room_id.clone())
+        }
+    });
+    let local = expect_context::<LocalState>();
+    let join = Action::new(move |_: &()| {
+        let room_id = room_id.clone();
+        async move {
+            match join_team(room_id).await {
+                Ok(()) => details.refetch(),
+                Err(e) => local.report(e),
+            }
+        }
+    });
+
+    view! {
+        <Suspense>
+        { move || details.get().and_then(Result::ok).flatten().map(|details| {
+            let rounds = details.history.len();
+            view! {
+                <div class="mt-6">
+                    <h3 class="font-semibold">"Team"</h3>
+                    <p class="text-sm">{ details.members.join(", ") }</p>
+                    { (!details.is_member).then(|| view! {
+                        <button
+                            class="btn btn-sm mt-2"
+                            disabled=move || join.pending().get()
+                            on:click=move |_| { join.dispatch(()); }
+                        >
+                            "Join team"
+                        </button>
+                    })}
+                    <div class="collapse collapse-arrow bg-base-200 mt-2">
+                        <input type="checkbox" />
+                        <div class="collapse-title font-semibold">{ format!("History ({rounds} rounds)") }</div>
+                        <div class="collapse-content">
+                            <ol class="text-sm">
+                                { details.history.iter().enumerate().map(|(i, round)| view! {
+                                    <li>
+                                        <span class="opacity-70">{ format!("#{} ", rounds - i) }</span>
+                                        { format_round(round) }
+                                    </li>
+                                }).collect_view() }
+                            </ol>
+                        </div>
+                    </div>
+                </div>
+            }
+        })}
+        </Suspense>
+    }
+}
+
+fn room_title(room_id: &RoomRef) -> String {
+    match room_id {
+        RoomRef::Id(id) => format!("Room #{id}"),
+        RoomRef::Slug(slug) => format!("Room {slug}"),
+    }
+}
+
 #[derive(Params, Clone, PartialEq)]
 struct PokerRoomId {
-    room_id: u64,
+    room_id: RoomRef,
 }
 
 /// Renders the home page of your application.
@@ -262,54 +812,110 @@ pub fn PokerRoom() -> impl IntoView {
             });
         }
     };
-    let game_state = game_state_updates(room_id);
+    let (game_state, error, reconnecting) = game_state_updates(room_id.clone());
+    let toasts = use_toasts();
+    provide_context(LocalState {
+        state: game_state,
+        toasts,
+    });
+    // Each reminder is shown once, even if the state is sent again
+    Effect::new(move |shown: Option<Option<u64>>| {
+        let nudge = game_state.with(|state| state.nudge.clone());
+        let id = nudge.as_ref().map(|nudge| nudge.id);
+        if let Some(nudge) = nudge.filter(|_| shown.flatten() != id) {
+            toasts.info(format!("{} is waiting for your vote", nudge.from));
+        }
+        id
+    });
     let avg_bet = Memo::new(move |_| {
         game_state.with(|state| {
-            let bets = state
-                .players
-                .iter()
-                .chain(iter::once(&state.self_state))
-                .filter_map(|state| state.card);
-            let sm: u64 = bets.clone().sum();
-            let cnt = bets.count();
+            let bets: Vec<u64> = match &state.anonymous_cards {
+                Some(cards) => cards.clone(),
+                None => state
+                    .players
+                    .iter()
+                    .chain(iter::once(&state.self_state))
+                    .filter_map(|state| state.card)
+                    .collect(),
+            };
+            let sm: u64 = bets.iter().sum();
+            let cnt = bets.len();
             if cnt == 0 { 0 } else { sm / cnt as u64 }
         })
     });
 
     let current_name = Memo::new(move |_| game_state.with(|state| state.self_state.name.clone()));
+    let spectator = Memo::new(move |_| game_state.with(|state| state.self_state.spectator));
+    let may_reveal = Memo::new(move |_| {
+        game_state
+            .with(|state| state.settings.reveal == RevealPermission::Everyone || state.is_host)
+    });
+    let title_room_id = room_id.clone();
+    let controls_room_id = room_id.clone();
+    let progress_room_id = room_id.clone();
+    let team_room_id = room_id.clone();
 
     Either::Right(view! {
         <div class="max-w-4xl mx-auto px-8 sm:px-4 lg:px-6 pt-6">
             <h1 class="text-base md:text-xl lg:text-3xl font-bold my-1 text-center">"Let's play poker!"</h1>
-            <h2 class="text-base md:text-lg lg:text-xl font-semibold my-1 text-center">"Room #" { room_id }</h2>
-            <div class="mt-2">
+            <h2 class="text-base md:text-lg lg:text-xl font-semibold my-1 text-center">
+                { move || game_state
+                    .with(|state| state.settings.title.clone())
+                    .unwrap_or_else(|| room_title(&title_room_id)) }
+            </h2>
+            { move || reconnecting.get().map(|cause| {
+                let message = match cause {
+                    Reconnecting::ServerRestarting => "Server is restarting, reconnecting...",
+                    Reconnecting::ConnectionLost => "Connection lost, reconnecting...",
+                };
+                view! { <div role="alert" class="alert alert-warning mt-2">{ message }</div> }
+            })}
+            { move || error.get().map(|e| {
+                let mut errors = Errors::default();
+                errors.insert_with_default_key(e);
+                view! { <ErrorTemplate outside_errors=errors /> }
+            })}
+            <div class="mt-2" class:hidden=move || error.read().is_some()>
                 <div>
                     <GameStateTable game_state=game_state />
                 </div>
-                <div class="mt-2">
+                { move || game_state
+                    .with(|state| state.anonymous_cards.clone())
+                    .map(|cards| view! { <div class="mt-2"><Distribution cards /></div> }) }
+                <div class="mt-2" class:hidden=spectator>
                     <CardChange
-                        cards=Memo::new(move |_| game_state.with(|state| state.cards.clone()))
+                        cards=Memo::new(move |_| game_state.with(|state| state.settings.cards.clone()))
                         self_card=Memo::new(move |_| game_state.with(|state| state.self_state.card))
-                        creds=room_id
+                        creds=room_id.clone()
                     />
                 </div>
-                <div class="mt-2">
+                <div class="mt-2 flex items-center gap-2">
                     <HideReveal
                         hidden=Memo::new(move |_| game_state.with(|state| state.hidden))
                         avg=avg_bet
-                        room_id=room_id
+                        allowed=may_reveal
+                        room_id=room_id.clone()
                     />
+                    <Countdown time_left=Memo::new(move |_| game_state.with(|state| state.time_left)) />
+                    <Show when=move || game_state.with(|state| state.hidden)>
+                        <VoteProgress game_state=game_state room_id=progress_room_id.clone() />
+                    </Show>
                 </div>
                 <div class="mt-2">
                 { move || {
                     view!{
                         <NameChange
                             current_name=current_name.get()
-                            room_id=room_id
+                            room_id=room_id.clone()
                         />
                     }
                 }}
                 </div>
+                <RoomControls game_state=game_state room_id=controls_room_id />
+                <TeamPanel
+                    room_id=team_room_id
+                    hidden=Memo::new(move |_| game_state.with(|state| state.hidden))
+                />
             </div>
         </div>
     })
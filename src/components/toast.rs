@@ -0,0 +1,98 @@
+use leptos::prelude::*;
+use std::time::Duration;
+
+/// How long a toast stays on the screen unless closed earlier
+const TOAST_LIFETIME: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToastKind {
+    Info,
+    Error,
+}
+
+#[derive(Clone, Debug)]
+struct Toast {
+    id: u64,
+    kind: ToastKind,
+    message: String,
+}
+
+/// Short-lived notifications shown in the corner of the screen, available to
+/// every component through the context
+#[derive(Clone, Copy)]
+pub struct Toasts {
+    toasts: RwSignal<Vec<Toast>>,
+    next_id: StoredValue<u64>,
+}
+
+impl Toasts {
+    pub fn push(self, kind: ToastKind, message: impl Into<String>) {
+        let id = self.next_id.get_value();
+        self.next_id.set_value(id + 1);
+        self.toasts.update(|toasts| {
+            toasts.push(Toast {
+                id,
+                kind,
+                message: message.into(),
+            })
+        });
+        set_timeout(move || self.dismiss(id), TOAST_LIFETIME);
+    }
+
+    pub fn info(self, message: impl Into<String>) {
+        self.push(ToastKind::Info, message);
+    }
+
+    pub fn error(self, message: impl Into<String>) {
+        self.push(ToastKind::Error, message);
+    }
+
+    fn dismiss(self, id: u64) {
+        // The toast might have outlived the page that showed it
+        let _ = self
+            .toasts
+            .try_update(|toasts| toasts.retain(|toast| toast.id != id));
+    }
+}
+
+pub fn provide_toasts() {
+    provide_context(Toasts {
+        toasts: RwSignal::new(Vec::new()),
+        next_id: StoredValue::new(0),
+    });
+}
+
+pub fn use_toasts() -> Toasts {
+    expect_context()
+}
+
+#[component]
+pub fn Toaster() -> impl IntoView {
+    let toasts = use_toasts();
+
+    view! {
+        <div class="toast toast-end z-50">
+            <For
+                each=move || toasts.toasts.get()
+                key=|toast| toast.id
+                children=move |toast| {
+                    let class = match toast.kind {
+                        ToastKind::Info => "alert alert-info",
+                        ToastKind::Error => "alert alert-error",
+                    };
+                    view! {
+                        <div role="alert" class=class>
+                            <span>{ toast.message }</span>
+                            <button
+                                class="btn btn-sm btn-ghost"
+                                on:click=move |_| toasts.dismiss(toast.id)
+                            >
+                                "✕"
+                            </button>
+                        </div>
+                    }
+                }
+            />
+        </div>
+    }
+}
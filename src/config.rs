@@ -0,0 +1,658 @@
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tower_sessions::cookie::SameSite;
+
+use crate::{
+    components::poker::room::api::parse_deck,
+    rate_limit::Quota,
+    telemetry::{LogConfig, LogFormat},
+};
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse config file {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Invalid value for {field}: {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NatsConfig {
+    pub url: String,
+    pub session_bucket: String,
+    /// Bucket for the teams, kept forever
+    pub team_bucket: String,
+    /// Hard limit on how long a session can live in the bucket
+    #[serde(with = "humantime_serde")]
+    pub bucket_max_age: Duration,
+    /// How many times to try reaching NATS on startup before giving up
+    pub connect_attempts: u32,
+    /// Deflate sessions before storing them. Sessions stored uncompressed stay
+    /// readable either way.
+    pub compress: bool,
+    /// Keys sessions are encrypted with, as `<id>:<base64 of 32 bytes>` with
+    /// an id from 1 to 255. The first one encrypts, all of them decrypt, so
+    /// a new key goes in front and the old one is dropped after
+    /// `bucket_max_age`. Empty to store sessions in the clear.
+    pub encryption_keys: Vec<String>,
+    /// Sessions stored before encryption was enabled are only read until
+    /// then, e.g. `2025-01-31T00:00:00Z`. At most `bucket_max_age` ahead, as
+    /// they are gone by then anyway.
+    #[serde(with = "humantime_serde")]
+    pub accept_unencrypted_until: Option<SystemTime>,
+}
+
+impl NatsConfig {
+    pub fn parse_encryption_keys(&self) -> Result<Vec<(u8, [u8; 32])>, ConfigError> {
+        use base64::{Engine, engine::general_purpose::STANDARD};
+
+        let invalid = |reason: &str| ConfigError::Invalid {
+            field: "nats.encryption_keys",
+            reason: reason.to_owned(),
+        };
+        let mut keys: Vec<(u8, [u8; 32])> = vec![];
+        for key in &self.encryption_keys {
+            let (id, key) = key
+                .split_once(':')
+                .ok_or_else(|| invalid("Expected <id>:<base64 key>"))?;
+            let id = match id.trim().parse::<u8>() {
+                Ok(id) if id > 0 => id,
+                _ => return Err(invalid("Key id has to be a number from 1 to 255")),
+            };
+            let key = STANDARD
+                .decode(key.trim())
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| invalid("Key has to be 32 bytes encoded as base64"))?;
+            if keys.iter().any(|(other, _)| *other == id) {
+                return Err(invalid(&format!("Key id {id} is used twice")));
+            }
+            keys.push((id, key));
+        }
+        Ok(keys)
+    }
+}
+
+impl Default for NatsConfig {
+    fn default() -> Self {
+        Self {
+            url: "nats://localhost:4222".to_owned(),
+            session_bucket: "sessions".to_owned(),
+            team_bucket: "teams".to_owned(),
+            bucket_max_age: Duration::from_secs(2 * 24 * 60 * 60),
+            connect_attempts: 5,
+            compress: false,
+            encryption_keys: vec![],
+            accept_unencrypted_until: None,
+        }
+    }
+}
+
+/// Where the sessions are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionBackend {
+    /// NATS key-value bucket, see the `nats` section
+    Nats,
+    /// Process memory, sessions are lost on restart
+    Memory,
+    /// Json files in `session.dir`
+    File,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub backend: SessionBackend,
+    /// Directory for the file backend
+    pub dir: PathBuf,
+    /// Sessions not used for this long expire
+    #[serde(with = "humantime_serde")]
+    pub inactivity_expiry: Duration,
+    /// How often expired sessions are purged from the store
+    #[serde(with = "humantime_serde")]
+    pub cleanup_interval: Duration,
+    pub secure_cookie: bool,
+    pub cookie_name: String,
+    /// Set to share the cookie with subdomains, e.g. `example.com`
+    pub cookie_domain: Option<String>,
+    /// Set when the server is mounted under a prefix by the reverse proxy
+    pub cookie_path: String,
+    pub cookie_same_site: CookieSameSite,
+    /// Base64 of at least 64 random bytes the session cookie is signed with,
+    /// e.g. `openssl rand -base64 64`. The cookie isn't signed without one.
+    pub cookie_key: Option<String>,
+}
+
+impl SessionConfig {
+    pub fn parse_cookie_key(&self) -> Result<Option<Vec<u8>>, ConfigError> {
+        use base64::{Engine, engine::general_purpose::STANDARD};
+
+        let Some(key) = &self.cookie_key else {
+            return Ok(None);
+        };
+        match STANDARD.decode(key.trim()) {
+            Ok(key) if key.len() >= 64 => Ok(Some(key)),
+            _ => Err(ConfigError::Invalid {
+                field: "session.cookie_key",
+                reason: "Has to be at least 64 bytes encoded as base64".to_owned(),
+            }),
+        }
+    }
+}
+
+/// Whether the name can be used for a cookie as is, see RFC 6265
+fn is_cookie_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&c))
+}
+
+/// When the browser sends the session cookie along with cross-site requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSameSite {
+    /// Only to requests originating from the site itself
+    Strict,
+    /// Also when following a link from another site
+    Lax,
+    /// Always, requires the cookie to be secure
+    None,
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(value: CookieSameSite) -> Self {
+        match value {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            backend: SessionBackend::Nats,
+            dir: "sessions".into(),
+            inactivity_expiry: Duration::from_secs(6 * 60 * 60),
+            cleanup_interval: Duration::from_secs(15 * 60),
+            secure_cookie: true,
+            cookie_name: "id".to_owned(),
+            cookie_domain: None,
+            cookie_path: "/".to_owned(),
+            cookie_same_site: CookieSameSite::Strict,
+            cookie_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// Deck of newly created rooms, e.g. `0.5, 1, 2, 3`
+    pub default_deck: String,
+    pub max_players: usize,
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        Self {
+            default_deck: "0.5, 1, 2, 3, 5, 8, 13, 21".to_owned(),
+            max_players: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub per_ip: Quota,
+    pub per_uid: Quota,
+    /// Reverse proxies in front of the server. Requests from them are limited
+    /// by the client from their `X-Forwarded-For` instead.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_ip: Quota {
+                burst: 100,
+                per_second: 50.,
+            },
+            per_uid: Quota {
+                burst: 30,
+                per_second: 10.,
+            },
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+/// Teams are kept by the same backend as the sessions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TeamsConfig {
+    /// Directory for the file backend
+    pub dir: PathBuf,
+    /// How many revealed rounds a team remembers
+    pub history: usize,
+}
+
+impl Default for TeamsConfig {
+    fn default() -> Self {
+        Self {
+            dir: "teams".into(),
+            history: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token for the `/admin` endpoints, they are disabled without one
+    pub token: Option<String>,
+}
+
+/// Single sign-on with an OpenID Connect provider, players can still play
+/// anonymously
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Provider to discover the endpoints of, e.g. `https://accounts.example.com`.
+    /// The login is disabled without one.
+    pub issuer_url: Option<String>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Where the provider sends players back to, `https://<host>/auth/callback`
+    pub redirect_url: String,
+    /// Scopes requested besides `openid`
+    pub scopes: Vec<String>,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer_url: None,
+            client_id: String::new(),
+            client_secret: None,
+            redirect_url: String::new(),
+            scopes: vec!["profile".to_owned(), "email".to_owned()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log: LogConfig,
+    /// How long to wait for connections to close on shutdown
+    #[serde(with = "humantime_serde")]
+    pub shutdown_timeout: Duration,
+    pub nats: NatsConfig,
+    pub session: SessionConfig,
+    pub rooms: RoomsConfig,
+    pub teams: TeamsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub admin: AdminConfig,
+    pub oidc: OidcConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log: Default::default(),
+            shutdown_timeout: Duration::from_secs(10),
+            nats: Default::default(),
+            session: Default::default(),
+            rooms: Default::default(),
+            teams: Default::default(),
+            rate_limit: Default::default(),
+            admin: Default::default(),
+            oidc: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason: &str| ConfigError::Invalid {
+            field,
+            reason: reason.to_owned(),
+        };
+        tracing_subscriber::EnvFilter::try_new(&self.log.filter)
+            .map_err(|e| invalid("log.filter", &e.to_string()))?;
+        if cfg!(not(feature = "otlp")) && self.log.otlp_endpoint.is_some() {
+            Err(invalid(
+                "log.otlp_endpoint",
+                "The server was built without the otlp feature",
+            ))?;
+        }
+        if self.nats.url.is_empty() {
+            Err(invalid("nats.url", "Has to be non-empty"))?;
+        }
+        if self.nats.session_bucket.is_empty() {
+            Err(invalid("nats.session_bucket", "Has to be non-empty"))?;
+        }
+        self.nats.parse_encryption_keys()?;
+        let latest_deadline = SystemTime::now() + self.nats.bucket_max_age;
+        if self
+            .nats
+            .accept_unencrypted_until
+            .is_some_and(|deadline| deadline > latest_deadline)
+        {
+            Err(invalid(
+                "nats.accept_unencrypted_until",
+                "Can't be more than nats.bucket_max_age ahead",
+            ))?;
+        }
+        if self.session.cleanup_interval.is_zero() {
+            Err(invalid("session.cleanup_interval", "Has to be positive"))?;
+        }
+        if self.nats.connect_attempts == 0 {
+            Err(invalid("nats.connect_attempts", "Has to be positive"))?;
+        }
+        if self.nats.bucket_max_age < self.session.inactivity_expiry {
+            Err(invalid(
+                "nats.bucket_max_age",
+                "Can't be less than session.inactivity_expiry",
+            ))?;
+        }
+        if self.session.inactivity_expiry.is_zero() {
+            Err(invalid("session.inactivity_expiry", "Has to be positive"))?;
+        }
+        if !is_cookie_name(&self.session.cookie_name) {
+            Err(invalid(
+                "session.cookie_name",
+                "Has to be non-empty and free of separators",
+            ))?;
+        }
+        if !self.session.cookie_path.starts_with('/') {
+            Err(invalid("session.cookie_path", "Has to start with /"))?;
+        }
+        if self.session.cookie_same_site == CookieSameSite::None && !self.session.secure_cookie {
+            Err(invalid(
+                "session.cookie_same_site",
+                "Can't be none unless the cookie is secure",
+            ))?;
+        }
+        self.session.parse_cookie_key()?;
+        parse_deck(&self.rooms.default_deck).map_err(|e| invalid("rooms.default_deck", &e))?;
+        if self.rooms.max_players == 0 {
+            Err(invalid("rooms.max_players", "Has to be positive"))?;
+        }
+        if self.nats.team_bucket.is_empty() {
+            Err(invalid("nats.team_bucket", "Has to be non-empty"))?;
+        }
+        if self.teams.history == 0 {
+            Err(invalid("teams.history", "Has to be positive"))?;
+        }
+        for (field, quota) in [
+            ("rate_limit.per_ip", self.rate_limit.per_ip),
+            ("rate_limit.per_uid", self.rate_limit.per_uid),
+        ] {
+            if quota.burst == 0 || !quota.per_second.is_finite() || quota.per_second <= 0. {
+                Err(invalid(field, "Burst and rate have to be positive"))?;
+            }
+        }
+        if self.admin.token.as_ref().is_some_and(|token| token.len() < 16) {
+            Err(invalid("admin.token", "Has to be at least 16 characters long"))?;
+        }
+        if let Some(issuer_url) = &self.oidc.issuer_url {
+            openidconnect::IssuerUrl::new(issuer_url.clone())
+                .map_err(|e| invalid("oidc.issuer_url", &e.to_string()))?;
+            if self.oidc.client_id.is_empty() {
+                Err(invalid("oidc.client_id", "Has to be set for the login"))?;
+            }
+            openidconnect::RedirectUrl::new(self.oidc.redirect_url.clone())
+                .map_err(|e| invalid("oidc.redirect_url", &e.to_string()))?;
+            // The callback is a navigation from the provider's site
+            if self.session.cookie_same_site == CookieSameSite::Strict {
+                Err(invalid(
+                    "session.cookie_same_site",
+                    "Has to be lax or none for the OIDC login",
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn default_cards(&self) -> Vec<u64> {
+        parse_deck(&self.rooms.default_deck).expect("Config to be validated")
+    }
+
+    /// The config with its secrets masked, safe to print. Encryption keys keep
+    /// their ids to tell which ones are set.
+    pub fn redacted(&self) -> Self {
+        const REDACTED: &str = "<redacted>";
+        let mut config = self.clone();
+        let redact = |secret: &mut Option<String>| {
+            if secret.is_some() {
+                *secret = Some(REDACTED.to_owned());
+            }
+        };
+        redact(&mut config.session.cookie_key);
+        redact(&mut config.admin.token);
+        redact(&mut config.oidc.client_secret);
+        for key in &mut config.nats.encryption_keys {
+            *key = match key.split_once(':') {
+                Some((id, _)) => format!("{id}:{REDACTED}"),
+                None => REDACTED.to_owned(),
+            };
+        }
+        config
+    }
+}
+
+/// Scrum poker server. Settings are taken from the config file, then from the
+/// environment and finally from the command line, latter taking precedence.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to a TOML config file
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    /// Print the resulting config and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Log filter, e.g. `info` or `info,scrum_poker=debug`
+    #[arg(long, env = "LOG_LEVEL")]
+    log_level: Option<String>,
+    #[arg(long, env = "LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// OTLP/HTTP collector to export spans to
+    #[arg(long, env = "OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+    /// How long to wait for connections to close on shutdown, e.g. `10s`
+    #[arg(long, env = "SHUTDOWN_TIMEOUT", value_parser = humantime::parse_duration)]
+    shutdown_timeout: Option<Duration>,
+    #[arg(long, env = "SESSION_BACKEND")]
+    session_backend: Option<SessionBackend>,
+    /// Directory for the file session backend
+    #[arg(long, env = "SESSION_DIR")]
+    session_dir: Option<PathBuf>,
+    #[arg(long, env = "NATS_URL")]
+    nats_url: Option<String>,
+    #[arg(long, env = "SESSION_BUCKET")]
+    session_bucket: Option<String>,
+    /// Deflate sessions stored in NATS
+    #[arg(long, env = "SESSION_COMPRESS")]
+    session_compress: Option<bool>,
+    /// Comma separated `<id>:<base64 key>` pairs to encrypt sessions in NATS with
+    #[arg(
+        long,
+        env = "SESSION_ENCRYPTION_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    session_encryption_keys: Option<Vec<String>>,
+    /// Keep reading sessions stored unencrypted until then, e.g. `2025-01-31T00:00:00Z`
+    #[arg(long, env = "SESSION_ACCEPT_UNENCRYPTED_UNTIL", value_parser = humantime::parse_rfc3339_weak)]
+    session_accept_unencrypted_until: Option<SystemTime>,
+    /// Hard limit on session lifetime, e.g. `2days`
+    #[arg(long, env = "SESSION_BUCKET_MAX_AGE", value_parser = humantime::parse_duration)]
+    session_bucket_max_age: Option<Duration>,
+    /// Sessions inactive for this long expire, e.g. `6h`
+    #[arg(long, env = "SESSION_EXPIRY", value_parser = humantime::parse_duration)]
+    session_expiry: Option<Duration>,
+    /// Whether the session cookie is only sent over https
+    #[arg(long, env = "SECURE_COOKIE")]
+    secure_cookie: Option<bool>,
+    /// Name of the session cookie
+    #[arg(long, env = "COOKIE_NAME")]
+    cookie_name: Option<String>,
+    /// Domain the session cookie is valid for, the current host if not set
+    #[arg(long, env = "COOKIE_DOMAIN")]
+    cookie_domain: Option<String>,
+    /// Path the session cookie is valid for
+    #[arg(long, env = "COOKIE_PATH")]
+    cookie_path: Option<String>,
+    #[arg(long, env = "COOKIE_SAME_SITE")]
+    cookie_same_site: Option<CookieSameSite>,
+    /// Base64 of at least 64 random bytes to sign the session cookie with
+    #[arg(long, env = "COOKIE_KEY", hide_env_values = true)]
+    cookie_key: Option<String>,
+    /// Deck of newly created rooms, e.g. `0.5, 1, 2, 3`
+    #[arg(long, env = "DEFAULT_DECK")]
+    default_deck: Option<String>,
+    #[arg(long, env = "MAX_PLAYERS_PER_ROOM")]
+    max_players_per_room: Option<usize>,
+    /// Comma separated addresses of the reverse proxies whose
+    /// `X-Forwarded-For` the rate limiter believes
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Option<Vec<IpAddr>>,
+    /// Bearer token enabling the `/admin` endpoints
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// OpenID Connect provider to let players sign in with
+    #[arg(long, env = "OIDC_ISSUER_URL")]
+    oidc_issuer_url: Option<String>,
+    #[arg(long, env = "OIDC_CLIENT_ID")]
+    oidc_client_id: Option<String>,
+    #[arg(long, env = "OIDC_CLIENT_SECRET", hide_env_values = true)]
+    oidc_client_secret: Option<String>,
+    /// Where the provider sends players back to, `https://<host>/auth/callback`
+    #[arg(long, env = "OIDC_REDIRECT_URL")]
+    oidc_redirect_url: Option<String>,
+}
+
+impl Cli {
+    pub fn load_config(&self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            None => Config::default(),
+            Some(path) => {
+                let content =
+                    std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+                        path: path.clone(),
+                        source,
+                    })?;
+                toml::from_str(&content).map_err(|source| ConfigError::Parse {
+                    path: path.clone(),
+                    source,
+                })?
+            }
+        };
+
+        fn set<T: Clone>(field: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *field = value.clone();
+            }
+        }
+        set(&mut config.log.filter, &self.log_level);
+        set(&mut config.log.format, &self.log_format);
+        if self.otlp_endpoint.is_some() {
+            config.log.otlp_endpoint = self.otlp_endpoint.clone();
+        }
+        set(&mut config.shutdown_timeout, &self.shutdown_timeout);
+        set(&mut config.session.backend, &self.session_backend);
+        set(&mut config.session.dir, &self.session_dir);
+        set(&mut config.nats.url, &self.nats_url);
+        set(&mut config.nats.session_bucket, &self.session_bucket);
+        set(
+            &mut config.nats.bucket_max_age,
+            &self.session_bucket_max_age,
+        );
+        set(&mut config.nats.compress, &self.session_compress);
+        set(&mut config.nats.encryption_keys, &self.session_encryption_keys);
+        if self.session_accept_unencrypted_until.is_some() {
+            config.nats.accept_unencrypted_until = self.session_accept_unencrypted_until;
+        }
+        set(&mut config.session.inactivity_expiry, &self.session_expiry);
+        set(&mut config.session.secure_cookie, &self.secure_cookie);
+        set(&mut config.session.cookie_name, &self.cookie_name);
+        if self.cookie_domain.is_some() {
+            config.session.cookie_domain = self.cookie_domain.clone();
+        }
+        set(&mut config.session.cookie_path, &self.cookie_path);
+        set(&mut config.session.cookie_same_site, &self.cookie_same_site);
+        if self.cookie_key.is_some() {
+            config.session.cookie_key = self.cookie_key.clone();
+        }
+        set(&mut config.rooms.default_deck, &self.default_deck);
+        set(&mut config.rooms.max_players, &self.max_players_per_room);
+        set(&mut config.rate_limit.trusted_proxies, &self.trusted_proxies);
+        if self.admin_token.is_some() {
+            config.admin.token = self.admin_token.clone();
+        }
+        if self.oidc_issuer_url.is_some() {
+            config.oidc.issuer_url = self.oidc_issuer_url.clone();
+        }
+        set(&mut config.oidc.client_id, &self.oidc_client_id);
+        if self.oidc_client_secret.is_some() {
+            config.oidc.client_secret = self.oidc_client_secret.clone();
+        }
+        set(&mut config.oidc.redirect_url, &self.oidc_redirect_url);
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted_masks_secrets() {
+        let mut config = Config::default();
+        config.session.cookie_key = Some("cookie-secret".to_owned());
+        config.admin.token = Some("admin-secret".to_owned());
+        config.oidc.client_secret = Some("oidc-secret".to_owned());
+        config.nats.encryption_keys = vec!["1:key-secret".to_owned(), "garbage".to_owned()];
+
+        let printed = toml::to_string_pretty(&config.redacted()).unwrap();
+        assert!(!printed.contains("-secret"), "{printed}");
+        assert!(printed.contains("1:<redacted>"));
+        assert_eq!(Config::default().redacted().admin.token, None);
+    }
+
+    #[test]
+    fn unencrypted_window_is_bounded() {
+        let mut config = Config::default();
+        config.nats.accept_unencrypted_until = Some(SystemTime::now() + Duration::from_secs(60));
+        assert!(config.validate().is_ok());
+
+        config.nats.accept_unencrypted_until =
+            Some(SystemTime::now() + config.nats.bucket_max_age + Duration::from_secs(60));
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,19 +1,125 @@
 use http::status::StatusCode;
-use leptos::prelude::*;
+use leptos::{either::Either, prelude::*};
+use leptos_router::hooks::use_location;
 use thiserror::Error;
 
+use crate::components::poker::room::api::JoinRoom;
+
 #[derive(Clone, Debug, Error)]
 pub enum AppError {
     #[error("Not Found")]
     NotFound,
+    #[error("Room Closed")]
+    RoomClosed,
+    #[error("Disconnected")]
+    Kicked,
+    #[error("Removed")]
+    Removed,
+    #[error("Forbidden")]
+    Forbidden,
+    #[error("Room Is Full")]
+    RoomFull,
+    #[error("Server Unavailable")]
+    ServerUnavailable,
+    #[error("Session Expired")]
+    SessionExpired,
 }
 
 impl AppError {
     pub fn status_code(&self) -> StatusCode {
         match self {
             AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::RoomClosed => StatusCode::GONE,
+            AppError::Kicked | AppError::Removed | AppError::RoomFull => StatusCode::CONFLICT,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::ServerUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::SessionExpired => StatusCode::UNAUTHORIZED,
         }
     }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            AppError::NotFound => "There's nothing here.",
+            AppError::RoomClosed => "The room doesn't exist anymore, rejoin to start it over.",
+            AppError::Kicked => "You were disconnected by joining this room from another tab.",
+            AppError::Removed => {
+                "You were removed from the room, e.g. by starting over as a new player."
+            }
+            AppError::Forbidden => "The room is private, enter its passcode to join.",
+            AppError::RoomFull => "There's no place left in the room, try again later.",
+            AppError::ServerUnavailable => "Can't reach the server, it might be restarting.",
+            AppError::SessionExpired => "Your session has expired, rejoin to get a new one.",
+        }
+    }
+
+    /// Whether reloading the page has a chance to get the player back in
+    fn can_rejoin(&self) -> bool {
+        !matches!(self, AppError::NotFound | AppError::Forbidden)
+    }
+}
+
+/// Passcode form of the private room the page is at, the room is reloaded
+/// once the player is admitted
+#[component]
+fn JoinPrivateRoom() -> impl IntoView {
+    let room_id = use_location()
+        .pathname
+        .get_untracked()
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+    let join_room = ServerAction::<JoinRoom>::new();
+    Effect::new(move || {
+        if let Some(Ok(())) = join_room.value().get() {
+            // Full reload on purpose, a fresh page opens a new connection
+            let _ = window().location().reload();
+        }
+    });
+    let join_error = move || {
+        join_room
+            .value()
+            .get()
+            .and_then(Result::err)
+            .map(|e| e.to_string())
+    };
+
+    view! {
+        <ActionForm action=join_room attr:class="flex">
+            <input type="hidden" name="room_id" value=room_id />
+            <input
+                type="password"
+                name="passcode"
+                placeholder="Passcode"
+                class="input input-bordered w-full max-w-xs"
+            />
+            <div class="w-2 h-auto"></div>
+            <input type="submit" class="btn" value="Join" />
+        </ActionForm>
+        { move || join_error().map(|e| view! {
+            <span class="label-text-alt text-error">{ e }</span>
+        })}
+    }
+}
+
+/// Ways out of the error page
+#[component]
+fn RecoveryActions(error: AppError) -> impl IntoView {
+    let location = use_location();
+    // Full reload on purpose, a fresh page opens a new connection
+    let current_page = move || format!("{}{}", location.pathname.get(), location.search.get());
+
+    view! {
+        <div class="flex flex-col items-center gap-2 mt-4">
+            { matches!(error, AppError::Forbidden).then(|| view! { <JoinPrivateRoom /> }) }
+            <div class="flex gap-2">
+                { error.can_rejoin().then(|| view! {
+                    <a href=current_page rel="external" class="btn btn-primary">"Rejoin"</a>
+                })}
+                <a href="/" rel="external" class="btn">"Go home"</a>
+            </div>
+        </div>
+    }
 }
 
 // A basic function to display errors served by the error boundaries.
@@ -51,19 +157,28 @@ pub fn ErrorTemplate(
         }
     }
 
+    // Only offer the way out of the first error, there's rarely more than one
+    let actions = match errors.first() {
+        Some(error) => Either::Left(view! { <RecoveryActions error=error.clone() /> }),
+        None => Either::Right(()),
+    };
+
     view! {
-        <h1>{if errors.len() > 1 {"Errors"} else {"Error"}}</h1>
-        <For
-            each= move || {errors.clone().into_iter().enumerate()}
-            key=|(index, _error)| *index
-            children=move |error| {
-                let error_string = error.1.to_string();
-                let error_code= error.1.status_code();
-                view! {
-                    <h2>{error_code.to_string()}</h2>
-                    <p>"Error: " {error_string}</p>
+        <div class="max-w-4xl mx-auto px-8 sm:px-4 lg:px-6 pt-6 text-center">
+            <h1 class="text-base md:text-xl lg:text-3xl font-bold my-2">{if errors.len() > 1 {"Errors"} else {"Error"}}</h1>
+            <For
+                each= move || {errors.clone().into_iter().enumerate()}
+                key=|(index, _error)| *index
+                children=move |error| {
+                    let error_string = error.1.to_string();
+                    let error_code= error.1.status_code();
+                    view! {
+                        <h2 class="text-base md:text-lg lg:text-xl font-semibold my-1">{error_code.to_string()} " " {error_string}</h2>
+                        <p>{error.1.description()}</p>
+                    }
                 }
-            }
-        />
+            />
+            { actions }
+        </div>
     }
 }
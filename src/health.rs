@@ -0,0 +1,26 @@
+use axum::{extract::State, response::IntoResponse};
+use http::StatusCode;
+use metrics_exporter_prometheus::PrometheusHandle;
+
+use crate::session_store::AnySessionStore;
+
+/// Liveness probe, the process is up and serving requests
+pub async fn healthz() -> impl IntoResponse {
+    "ok"
+}
+
+/// Readiness probe, the session store is reachable
+pub async fn readyz(State(store): State<AnySessionStore>) -> impl IntoResponse {
+    match store.ping().await {
+        Ok(()) => (StatusCode::OK, "ok".to_owned()),
+        Err(e) => {
+            tracing::warn!("Session store is unavailable: {e}");
+            (StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+        }
+    }
+}
+
+/// Prometheus metrics in the text exposition format
+pub async fn metrics(State(handle): State<PrometheusHandle>) -> impl IntoResponse {
+    handle.render()
+}
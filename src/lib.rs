@@ -3,8 +3,15 @@ pub mod error_template;
 pub mod macros;
 
 if_backend! {
+    pub mod admin;
+    pub mod auth;
+    pub mod config;
+    pub mod health;
     pub mod random_nickname;
+    pub mod rate_limit;
     pub mod session_store;
+    pub mod team_store;
+    pub mod telemetry;
     pub mod uid;
 }
 pub mod components;
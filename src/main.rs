@@ -1,100 +1,412 @@
 #![feature(impl_trait_in_fn_trait_return)]
 
-use time::ext::{NumericalDuration, NumericalStdDuration};
-
 use async_nats::jetstream;
 use axum::extract::FromRef;
-use leptos::prelude::*;
+use clap::Parser;
+use leptos::{config::errors::LeptosConfigError, prelude::*};
 use leptos_axum::AxumRouteListing;
-use scrum_poker::{components::poker::room::backend::ServerState, session_store::NatsSessionStore};
-use tower_sessions::{Expiry, SessionManagerLayer};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use scrum_poker::{
+    admin::{self, AdminToken, SessionCookie},
+    auth::{self, OidcLogin},
+    components::poker::room::backend::{RoomDefaults, ServerState},
+    config::{Cli, ConfigError, NatsConfig, SessionBackend},
+    health,
+    rate_limit::{RateLimiter, rate_limit},
+    session_store::{
+        AnySessionStore, FileSessionStore, MemorySessionStore, NatsSessionStore, RecordCodec,
+    },
+    team_store::TeamStore,
+    telemetry,
+};
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode, time::Duration};
+use thiserror::Error;
+use tower::util::Either;
+use tower_sessions::{Expiry, SessionManagerLayer, cookie::Key, session_store::ExpiredDeletion};
 
 #[derive(FromRef, Debug, Clone)]
 struct GlobalAppState {
     leptos_options: LeptosOptions,
     routes: Vec<AxumRouteListing>,
     server_state: ServerState,
+    session_store: AnySessionStore,
+    metrics: PrometheusHandle,
+    admin_token: AdminToken,
+    session_cookie: SessionCookie,
+    oidc: OidcLogin,
 }
 
-struct EnvVar<'a> {
-    name: &'a str,
-    default: &'a str,
+/// Everything that can stop the server from starting, each with its own exit code
+#[derive(Debug, Error)]
+enum StartupError {
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+    #[error("Failed to set up logging: {0}")]
+    Telemetry(String),
+    #[error(
+        "Can't connect to NATS at {url}: {source}. Check that the server is running and \
+         reachable, or use SESSION_BACKEND=memory for local development"
+    )]
+    NatsConnect {
+        url: String,
+        source: async_nats::ConnectError,
+    },
+    #[error(
+        "Can't create session bucket {bucket:?}: {source}. Check that JetStream is enabled \
+         on the NATS server (nats-server -js)"
+    )]
+    NatsBucket {
+        bucket: String,
+        source: jetstream::context::CreateKeyValueError,
+    },
+    #[error("Can't discover the OIDC provider at {issuer}: {source}")]
+    OidcDiscovery {
+        issuer: String,
+        source: auth::DiscoveryError,
+    },
+    #[error("Can't use session directory {path:?}: {source}")]
+    SessionDir {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Can't use team directory {path:?}: {source}")]
+    TeamDir {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error(
+        "Invalid leptos configuration: {0}. Check [package.metadata.leptos] and LEPTOS_* variables"
+    )]
+    Leptos(#[from] LeptosConfigError),
+    #[error("Failed to install metrics recorder: {0}")]
+    Metrics(#[from] metrics_exporter_prometheus::BuildError),
+    #[error(
+        "Can't listen on {addr}: {source}. Is the port already in use? Set LEPTOS_SITE_ADDR to change it"
+    )]
+    Bind {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+    #[error("Server failed: {0}")]
+    Serve(std::io::Error),
 }
 
-impl<'a> EnvVar<'a> {
-    const fn new(name: &'a str, default: &'a str) -> Self {
-        Self { name, default }
+impl StartupError {
+    /// Exit codes follow sysexits.h
+    fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config(_) | Self::Leptos(_) | Self::Telemetry(_) => 78, // EX_CONFIG
+            Self::NatsConnect { .. } | Self::NatsBucket { .. } => 69,     // EX_UNAVAILABLE
+            Self::OidcDiscovery { .. } => 69,                             // EX_UNAVAILABLE
+            Self::SessionDir { .. } | Self::TeamDir { .. } => 73,         // EX_CANTCREAT
+            Self::Bind { .. } => 71,                                      // EX_OSERR
+            Self::Metrics(_) | Self::Serve(_) => 70,                      // EX_SOFTWARE
+        }
     }
 
-    fn get(&self) -> String {
-        std::env::var(&self.name).unwrap_or_else(|_| {
-            tracing::warn!(
-                "No value provided for {}, using default value instead: {}",
-                self.name,
-                self.default
-            );
-            self.default.to_string()
-        })
+    /// Whether trying again a bit later has a chance to succeed
+    fn is_transient(&self) -> bool {
+        use async_nats::ConnectErrorKind as Connect;
+        use jetstream::context::CreateKeyValueErrorKind as Bucket;
+
+        match self {
+            Self::NatsConnect { source, .. } => matches!(
+                source.kind(),
+                Connect::Dns | Connect::TimedOut | Connect::Io | Connect::MaxReconnects
+            ),
+            Self::NatsBucket { source, .. } => {
+                matches!(source.kind(), Bucket::TimedOut | Bucket::JetStream)
+            }
+            _ => false,
+        }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    const NATS_URL: EnvVar<'static> = EnvVar::new("NATS_URL", "nats://localhost:4222");
-    const SESSIONS_BUCKET: EnvVar<'static> = EnvVar::new("SESSION_BUCKET", "sessions");
-
-    use axum::Router;
-    use leptos_axum::{LeptosRoutes, generate_route_list};
-    use scrum_poker::app::*;
-
-    tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(tracing::Level::DEBUG)
-        .with_level(true)
-        .init();
-
-    let nats_url = NATS_URL.get();
-    let sessions_bucket = SESSIONS_BUCKET.get();
-
-    let client = async_nats::connect(nats_url).await.unwrap();
+async fn try_connect_nats(
+    config: &NatsConfig,
+    codec: &RecordCodec,
+) -> Result<(NatsSessionStore, TeamStore), StartupError> {
+    let client =
+        async_nats::connect(&config.url)
+            .await
+            .map_err(|source| StartupError::NatsConnect {
+                url: config.url.clone(),
+                source,
+            })?;
     let js = jetstream::new(client);
 
     let bucket = js
         .create_or_update_key_value(jetstream::kv::Config {
-            bucket: sessions_bucket,
+            bucket: config.session_bucket.clone(),
             description: "".to_string(),
             max_value_size: 1024,
             history: 0,
-            max_age: 2.std_days(),
+            max_age: config.bucket_max_age,
             num_replicas: 1,
             ..Default::default()
         })
         .await
-        .unwrap();
-    let session_store = NatsSessionStore::new(bucket);
-    let session_manager = SessionManagerLayer::new(session_store)
-        .with_expiry(Expiry::OnInactivity(6.hours()))
-        .with_secure(true);
+        .map_err(|source| StartupError::NatsBucket {
+            bucket: config.session_bucket.clone(),
+            source,
+        })?;
+    let teams = js
+        .create_or_update_key_value(jetstream::kv::Config {
+            bucket: config.team_bucket.clone(),
+            history: 1,
+            num_replicas: 1,
+            ..Default::default()
+        })
+        .await
+        .map_err(|source| StartupError::NatsBucket {
+            bucket: config.team_bucket.clone(),
+            source,
+        })?;
+    Ok((
+        NatsSessionStore::new(bucket, codec.clone()),
+        TeamStore::Nats(Box::new(teams)),
+    ))
+}
+
+/// NATS is often started alongside the server, so give it a few chances to come up
+async fn connect_nats(config: &NatsConfig) -> Result<(NatsSessionStore, TeamStore), StartupError> {
+    let codec = RecordCodec::new(config.compress, &config.parse_encryption_keys()?)
+        .accept_unencrypted_until(config.accept_unencrypted_until);
+    let mut delay = Duration::from_millis(500);
+    for attempt in 1.. {
+        match try_connect_nats(config, &codec).await {
+            Err(e) if e.is_transient() && attempt < config.connect_attempts => {
+                tracing::warn!(
+                    "{e} (attempt {attempt}/{}), retrying in {delay:?}",
+                    config.connect_attempts
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(8));
+            }
+            result => return result,
+        }
+    }
+    unreachable!()
+}
+
+/// Unlike `ExpiredDeletion::continuously_delete_expired`, keeps going after
+/// failures: NATS being briefly unavailable shouldn't stop the cleanup for good
+async fn delete_expired_sessions(store: AnySessionStore, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(e) = store.delete_expired().await {
+            tracing::warn!("Failed to delete expired sessions: {e}");
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM, only Ctrl+C stops the server: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Stops accepting new connections once a signal is received and exits
+/// forcefully if closing them and the rooms takes too long
+async fn graceful_shutdown(timeout: Duration) {
+    shutdown_signal().await;
+    tracing::info!("Shutting down, waiting up to {timeout:?} for connections to close");
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        tracing::error!("Graceful shutdown timed out");
+        std::process::exit(1);
+    });
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // Logging might not be set up yet, so don't rely on it
+            eprintln!("Error: {e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
 
-    let conf = get_configuration(None).unwrap();
+async fn run() -> Result<(), StartupError> {
+    use axum::{
+        Router, middleware,
+        routing::{delete, get, post},
+    };
+    use leptos_axum::{LeptosRoutes, generate_route_list};
+    use scrum_poker::app::*;
+
+    let cli = Cli::parse();
+    let config = cli.load_config()?;
+    if cli.print_config {
+        print!(
+            "{}",
+            toml::to_string_pretty(&config.redacted()).expect("Config is always serializable")
+        );
+        return Ok(());
+    }
+
+    let _telemetry = telemetry::init(&config.log).map_err(StartupError::Telemetry)?;
+
+    let (session_store, team_store) = match config.session.backend {
+        SessionBackend::Nats => {
+            let (store, teams) = connect_nats(&config.nats).await?;
+            (AnySessionStore::Nats(Box::new(store)), teams)
+        }
+        SessionBackend::Memory => {
+            tracing::warn!(
+                "Using in-memory session store, sessions and teams will be lost on restart"
+            );
+            (
+                AnySessionStore::Memory(MemorySessionStore::default()),
+                TeamStore::default(),
+            )
+        }
+        SessionBackend::File => {
+            let store = FileSessionStore::new(&config.session.dir)
+                .await
+                .map_err(|source| StartupError::SessionDir {
+                    path: config.session.dir.clone(),
+                    source,
+                })?;
+            let teams = TeamStore::file(&config.teams.dir).await.map_err(|source| {
+                StartupError::TeamDir {
+                    path: config.teams.dir.clone(),
+                    source,
+                }
+            })?;
+            (AnySessionStore::File(store), teams)
+        }
+    };
+    tokio::spawn(delete_expired_sessions(
+        session_store.clone(),
+        config.session.cleanup_interval,
+    ));
+    let inactivity_expiry =
+        config
+            .session
+            .inactivity_expiry
+            .try_into()
+            .map_err(|_| ConfigError::Invalid {
+                field: "session.inactivity_expiry",
+                reason: "Too large".to_owned(),
+            })?;
+    let mut session_manager = SessionManagerLayer::new(session_store.clone())
+        .with_expiry(Expiry::OnInactivity(inactivity_expiry))
+        .with_secure(config.session.secure_cookie)
+        .with_name(config.session.cookie_name.clone())
+        .with_path(config.session.cookie_path.clone())
+        .with_same_site(config.session.cookie_same_site.into());
+    if let Some(domain) = &config.session.cookie_domain {
+        session_manager = session_manager.with_domain(domain.clone());
+    }
+    let cookie_key = config
+        .session
+        .parse_cookie_key()?
+        .map(|key| Key::from(&key));
+    let session_cookie = SessionCookie::new(config.session.cookie_name.clone(), cookie_key.clone());
+    let session_manager = match cookie_key {
+        Some(key) => Either::Left(session_manager.with_signed(key)),
+        None => {
+            tracing::warn!("No session.cookie_key configured, session cookies are not signed");
+            Either::Right(session_manager)
+        }
+    };
+
+    let oidc =
+        OidcLogin::discover(&config.oidc)
+            .await
+            .map_err(|source| StartupError::OidcDiscovery {
+                issuer: config.oidc.issuer_url.clone().unwrap_or_default(),
+                source,
+            })?;
+
+    let conf = get_configuration(None)?;
     let leptos_options = conf.leptos_options;
     let addr = leptos_options.site_addr;
     let routes = generate_route_list(App);
 
-    let server_state = ServerState::new();
+    let server_state = ServerState::new(
+        RoomDefaults {
+            cards: config.default_cards(),
+            max_players: config.rooms.max_players,
+            team_history: config.teams.history,
+        },
+        team_store,
+    );
+    let metrics = PrometheusBuilder::new().install_recorder()?;
+    tokio::spawn({
+        let metrics = metrics.clone();
+        async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                metrics.run_upkeep();
+            }
+        }
+    });
+    let rate_limiter = RateLimiter::new(
+        config.rate_limit.per_ip,
+        config.rate_limit.per_uid,
+        config.rate_limit.trusted_proxies.clone(),
+    );
+    tokio::spawn(
+        rate_limiter
+            .clone()
+            .sweep_periodically(Duration::from_secs(10)),
+    );
+
     let server_state = GlobalAppState {
         server_state,
         leptos_options,
         routes: routes.clone(),
+        session_store,
+        metrics,
+        admin_token: AdminToken::new(config.admin.token.clone()),
+        session_cookie,
+        oidc,
     };
 
+    let room_state = server_state.server_state.clone();
     let app = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/metrics", get(health::metrics))
+        .route("/admin/sessions/{id}", delete(admin::revoke_session))
+        .route("/auth/login", get(auth::login))
+        .route("/auth/callback", get(auth::callback))
+        .route("/auth/logout", post(auth::logout))
         .leptos_routes_with_context(
             &server_state,
             routes,
             {
-                let server_state = server_state.server_state.clone();
+                let server_state = server_state.clone();
                 move || {
-                    provide_context(server_state.clone());
+                    provide_context(server_state.server_state.clone());
+                    provide_context(server_state.session_store.clone());
+                    provide_context(server_state.oidc.clone());
                 }
             },
             {
@@ -105,12 +417,24 @@ async fn main() {
         .fallback(leptos_axum::file_and_error_handler::<GlobalAppState, _>(
             shell,
         ))
+        .layer(middleware::from_fn_with_state(rate_limiter, rate_limit))
         .layer(session_manager)
         .with_state(server_state);
 
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    tracing::info!("listening on http://{addr}");
-    axum::serve(listener, app.into_make_service())
+    let listener = tokio::net::TcpListener::bind(&addr)
         .await
-        .unwrap();
+        .map_err(|source| StartupError::Bind { addr, source })?;
+    tracing::info!("listening on http://{addr}");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(graceful_shutdown(config.shutdown_timeout))
+    .await
+    .map_err(StartupError::Serve)?;
+    // Websockets outlive the http connections they were upgraded from, the
+    // players are asked to reconnect and the team rooms saved only now
+    room_state.shutdown().await;
+    tracing::info!("Bye");
+    Ok(())
 }
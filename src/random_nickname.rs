@@ -353,3 +353,39 @@ pub fn gen_nickname(uid: u128) -> String {
     FIRST[(uid % FIRST.len() as u128) as usize].to_owned()
         + SECOND[(uid % SECOND.len() as u128) as usize]
 }
+
+/// Generates a room slug like `brave-turing-42`
+pub fn gen_slug(seed: u128) -> String {
+    let first = FIRST[(seed % FIRST.len() as u128) as usize];
+    let seed = seed / FIRST.len() as u128;
+    let second = SECOND[(seed % SECOND.len() as u128) as usize];
+    let seed = seed / SECOND.len() as u128;
+    format!(
+        "{}-{}-{}",
+        first.to_lowercase(),
+        second.to_lowercase(),
+        seed % 100
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::poker::room::api::check_room_slug;
+    use std::collections::HashSet;
+
+    #[test]
+    fn slugs_are_valid() {
+        for seed in 0..(FIRST.len() * SECOND.len()) as u128 {
+            let slug = gen_slug(seed * 100 + 99);
+            assert_eq!(check_room_slug(&slug), Ok(()), "{slug}");
+        }
+    }
+
+    #[test]
+    fn distinct_seeds_give_distinct_slugs() {
+        let slugs: HashSet<_> = (0..100_000).map(gen_slug).collect();
+        assert_eq!(slugs.len(), 100_000);
+        assert_eq!(gen_slug(42), gen_slug(42));
+    }
+}
@@ -0,0 +1,230 @@
+use axum::{
+    Json,
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{HeaderMap, StatusCode, header::RETRY_AFTER};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tower_sessions::Session;
+
+use crate::{components::poker::room::api::ServerError, uid::get_uid};
+
+/// Buckets that weren't touched for this long are considered full and get
+/// dropped by [`RateLimiter::sweep`]
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Quota {
+    /// How many requests can be made in a quick succession
+    pub burst: u32,
+    /// How many requests per second are replenished
+    pub per_second: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    Uid(u128),
+}
+
+impl Key {
+    fn kind(&self) -> &'static str {
+        match self {
+            Key::Ip(_) => "ip",
+            Key::Uid(_) => "uid",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter for server functions, keyed both by the client ip
+/// and by the session uid
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    per_ip: Quota,
+    per_uid: Quota,
+    /// Peers whose `X-Forwarded-For` is believed
+    trusted_proxies: Arc<[IpAddr]>,
+    buckets: Arc<Mutex<HashMap<Key, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_ip: Quota, per_uid: Quota, trusted_proxies: Vec<IpAddr>) -> Self {
+        Self {
+            per_ip,
+            per_uid,
+            trusted_proxies: trusted_proxies.into(),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Drops the idle buckets, every `period` until the server stops
+    pub async fn sweep_periodically(self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.sweep(Instant::now());
+        }
+    }
+
+    fn sweep(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, bucket| now - bucket.updated < IDLE_BUCKET_TTL);
+        metrics::gauge!("rate_limit_buckets").set(buckets.len() as f64);
+    }
+
+    /// The client is the last `X-Forwarded-For` hop that isn't a trusted
+    /// proxy, as any earlier one could've been made up by the client itself
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusted_proxies.contains(&peer) {
+            return peer;
+        }
+        let hops = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|hop| hop.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            // Garbage can't be told apart from a spoofed hop, stop at it
+            let Some(hop) = hop else { break };
+            client = hop;
+            if !self.trusted_proxies.contains(&hop) {
+                break;
+            }
+        }
+        client
+    }
+
+    fn try_acquire(&self, key: Key, now: Instant) -> bool {
+        let quota = match key {
+            Key::Ip(_) => self.per_ip,
+            Key::Uid(_) => self.per_uid,
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: quota.burst as f64,
+            updated: now,
+        });
+        let elapsed = (now - bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * quota.per_second).min(quota.burst as f64);
+        bucket.updated = now;
+        if bucket.tokens < 1. {
+            return false;
+        }
+        bucket.tokens -= 1.;
+        true
+    }
+}
+
+/// Middleware rejecting server function calls over the quota with `429 Too Many Requests`
+pub async fn rate_limit(
+    State(limiter): State<RateLimiter>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !request.uri().path().starts_with("/api/") {
+        return next.run(request).await;
+    }
+
+    let ip = limiter.client_ip(addr.ip(), request.headers());
+    let mut keys = vec![Key::Ip(ip)];
+    match get_uid(&session).await {
+        Ok(Some(uid)) => keys.push(Key::Uid(uid)),
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Failed to retrieve uid for rate limiting: {e}"),
+    }
+
+    for key in keys {
+        if !limiter.try_acquire(key, Instant::now()) {
+            tracing::debug!("Rate limited {key:?} on {}", request.uri().path());
+            metrics::counter!("rate_limited_requests_total", "key" => key.kind()).increment(1);
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, "1")],
+                // Same shape server functions report their errors in
+                Json(ServerError::RateLimited),
+            )
+                .into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTA: Quota = Quota {
+        burst: 2,
+        per_second: 1.,
+    };
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limiter = RateLimiter::new(QUOTA, QUOTA, vec![]);
+        let key = Key::Uid(1);
+        let now = Instant::now();
+        assert!(limiter.try_acquire(key, now));
+        assert!(limiter.try_acquire(key, now));
+        assert!(!limiter.try_acquire(key, now));
+        assert!(limiter.try_acquire(Key::Uid(2), now));
+        assert!(limiter.try_acquire(key, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn sweep_drops_idle_buckets() {
+        let limiter = RateLimiter::new(QUOTA, QUOTA, vec![]);
+        let now = Instant::now();
+        limiter.try_acquire(Key::Uid(1), now);
+        limiter.try_acquire(Key::Uid(2), now + IDLE_BUCKET_TTL);
+        limiter.sweep(now + IDLE_BUCKET_TTL);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key(&Key::Uid(1)));
+        assert!(buckets.contains_key(&Key::Uid(2)));
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let limiter = RateLimiter::new(QUOTA, QUOTA, vec![ip("10.0.0.1")]);
+        let headers = forwarded_for("1.2.3.4");
+        assert_eq!(limiter.client_ip(ip("5.6.7.8"), &headers), ip("5.6.7.8"));
+    }
+
+    #[test]
+    fn forwarded_for_skips_trusted_hops_only() {
+        let limiter = RateLimiter::new(QUOTA, QUOTA, vec![ip("10.0.0.1"), ip("10.0.0.2")]);
+        let headers = forwarded_for("6.6.6.6, 1.2.3.4, 10.0.0.2");
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("1.2.3.4"));
+        let headers = forwarded_for("bogus, 10.0.0.2");
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &HeaderMap::new()), ip("10.0.0.1"));
+    }
+}
@@ -1,84 +1,232 @@
-use async_nats::jetstream::kv::{CreateErrorKind, Store};
 use async_trait::async_trait;
-use rand::random;
 use tower_sessions::{
-    session::{Id, Record},
-    session_store::{Error, Result},
     SessionStore,
+    session::{Id, Record},
+    session_store::{ExpiredDeletion, Result},
 };
 
-fn to_nats_key(v: &Id) -> String {
-    format!("{:X}", v.0)
-}
+mod codec;
+mod file;
+mod memory;
+mod nats;
 
-fn serialize(record: &Record) -> Result<Vec<u8>> {
-    serde_json::to_vec(record).map_err(|e| Error::Encode(e.to_string()))
-}
+pub use codec::RecordCodec;
+pub use file::FileSessionStore;
+pub use memory::MemorySessionStore;
+pub use nats::NatsSessionStore;
 
+/// Session store picked at startup according to the config
 #[derive(Debug, Clone)]
-pub struct NatsSessionStore {
-    client: Store,
+pub enum AnySessionStore {
+    Nats(Box<NatsSessionStore>),
+    Memory(MemorySessionStore),
+    File(FileSessionStore),
 }
 
-impl NatsSessionStore {
-    pub fn new(client: Store) -> Self {
-        Self { client }
+impl AnySessionStore {
+    /// Checks that the underlying storage is reachable
+    pub async fn ping(&self) -> Result<()> {
+        match self {
+            Self::Nats(store) => store.ping().await,
+            Self::Memory(_) => Ok(()),
+            Self::File(store) => store.ping().await,
+        }
+    }
+
+    /// Stores the serialized identity of the session unless it already has
+    /// one, and returns the identity that ended up stored. Concurrent requests
+    /// of the same session all get the same identity this way.
+    pub async fn create_identity(&self, session_id: &Id, identity: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Self::Nats(store) => store.create_identity(session_id, identity).await,
+            Self::Memory(store) => store.create_identity(session_id, identity).await,
+            Self::File(store) => store.create_identity(session_id, identity).await,
+        }
     }
 }
 
 #[async_trait]
-impl SessionStore for NatsSessionStore {
+impl SessionStore for AnySessionStore {
     async fn create(&self, session_record: &mut Record) -> Result<()> {
-        loop {
-            let result = self
-                .client
-                .create(
-                    to_nats_key(&session_record.id),
-                    serialize(session_record)?.into(),
-                )
-                .await;
-            match result {
-                Ok(_) => return Ok(()),
-                Err(e) if e.kind() == CreateErrorKind::AlreadyExists => {
-                    tracing::warn!("Collision on record key {}", session_record.id.0);
-                }
-                Err(e) => {
-                    return Err(Error::Backend(e.to_string()));
-                }
-            }
-            session_record.id.0 = random();
+        match self {
+            Self::Nats(store) => store.create(session_record).await,
+            Self::Memory(store) => store.create(session_record).await,
+            Self::File(store) => store.create(session_record).await,
         }
     }
 
     async fn save(&self, session_record: &Record) -> Result<()> {
-        self.client
-            .put(
-                to_nats_key(&session_record.id),
-                serialize(session_record)?.into(),
-            )
-            .await
-            .map_err(|e| Error::Backend(e.to_string()))?;
-        Ok(())
+        match self {
+            Self::Nats(store) => store.save(session_record).await,
+            Self::Memory(store) => store.save(session_record).await,
+            Self::File(store) => store.save(session_record).await,
+        }
     }
 
     async fn load(&self, session_id: &Id) -> Result<Option<Record>> {
-        let record = self
-            .client
-            .get(to_nats_key(session_id))
-            .await
-            .map_err(|e| Error::Backend(e.to_string()))?;
-        let record = match record {
-            None => return Ok(None),
-            Some(r) => r,
-        };
-        let record = serde_json::from_slice(&record).map_err(|e| Error::Decode(e.to_string()))?;
-        Ok(Some(record))
+        match self {
+            Self::Nats(store) => store.load(session_id).await,
+            Self::Memory(store) => store.load(session_id).await,
+            Self::File(store) => store.load(session_id).await,
+        }
     }
 
     async fn delete(&self, session_id: &Id) -> Result<()> {
-        self.client
-            .delete(to_nats_key(session_id))
+        match self {
+            Self::Nats(store) => store.delete(session_id).await,
+            Self::Memory(store) => store.delete(session_id).await,
+            Self::File(store) => store.delete(session_id).await,
+        }
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for AnySessionStore {
+    async fn delete_expired(&self) -> Result<()> {
+        match self {
+            Self::Nats(store) => store.delete_expired().await,
+            Self::Memory(store) => store.delete_expired().await,
+            Self::File(store) => store.delete_expired().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_nats::jetstream;
+    use rand::random;
+    use time::{Duration, OffsetDateTime};
+    use tower_sessions::SessionStore;
+
+    fn record(expires_in: Duration) -> Record {
+        Record {
+            id: Id::default(),
+            data: [("key".to_owned(), serde_json::json!("value"))].into(),
+            expiry_date: OffsetDateTime::now_utc() + expires_in,
+        }
+    }
+
+    /// What every backend has to provide
+    async fn suite(store: AnySessionStore) {
+        store.ping().await.unwrap();
+
+        // Round trip
+        let mut active = record(Duration::hours(1));
+        store.create(&mut active).await.unwrap();
+        assert_eq!(store.load(&active.id).await.unwrap(), Some(active.clone()));
+        active.data.insert("other".to_owned(), serde_json::json!(42));
+        store.save(&active).await.unwrap();
+        assert_eq!(store.load(&active.id).await.unwrap(), Some(active.clone()));
+
+        // Colliding ids are replaced, the existing session stays untouched
+        let mut colliding = record(Duration::hours(1));
+        colliding.id = active.id;
+        store.create(&mut colliding).await.unwrap();
+        assert_ne!(colliding.id, active.id);
+        assert_eq!(store.load(&active.id).await.unwrap(), Some(active.clone()));
+        assert_eq!(store.load(&colliding.id).await.unwrap(), Some(colliding.clone()));
+
+        // The first identity wins
+        let identity = store.create_identity(&active.id, b"first".to_vec()).await.unwrap();
+        assert_eq!(identity, b"first");
+        let identity = store.create_identity(&active.id, b"second".to_vec()).await.unwrap();
+        assert_eq!(identity, b"first");
+
+        // Deleting takes the identity along
+        store.delete(&active.id).await.unwrap();
+        assert_eq!(store.load(&active.id).await.unwrap(), None);
+        let identity = store.create_identity(&active.id, b"third".to_vec()).await.unwrap();
+        assert_eq!(identity, b"third");
+        store.delete(&active.id).await.unwrap();
+
+        // Expired sessions are gone, their identities with them
+        let mut expired = record(Duration::seconds(-1));
+        store.create(&mut expired).await.unwrap();
+        assert_eq!(store.load(&expired.id).await.unwrap(), None);
+        let mut expired = record(Duration::seconds(-1));
+        store.create(&mut expired).await.unwrap();
+        store.create_identity(&expired.id, b"expired".to_vec()).await.unwrap();
+        store.delete_expired().await.unwrap();
+        assert_eq!(store.load(&expired.id).await.unwrap(), None);
+        let identity = store.create_identity(&expired.id, b"fresh".to_vec()).await.unwrap();
+        assert_eq!(identity, b"fresh");
+        store.delete(&expired.id).await.unwrap();
+        assert_eq!(store.load(&colliding.id).await.unwrap(), Some(colliding.clone()));
+
+        // Identities whose session is gone are purged too
+        let orphan = Id::default();
+        store.create_identity(&orphan, b"orphan".to_vec()).await.unwrap();
+        store.delete_expired().await.unwrap();
+        let identity = store.create_identity(&orphan, b"fresh".to_vec()).await.unwrap();
+        assert_eq!(identity, b"fresh");
+        store.delete(&orphan).await.unwrap();
+
+        // Missing sessions are no error
+        store.delete(&Id::default()).await.unwrap();
+        assert_eq!(store.load(&Id::default()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn memory_store() {
+        suite(AnySessionStore::Memory(MemorySessionStore::default())).await;
+    }
+
+    #[tokio::test]
+    async fn file_store() {
+        let dir = std::env::temp_dir().join(format!("scrum-poker-sessions-{:x}", random::<u64>()));
+        let store = FileSessionStore::new(&dir).await.unwrap();
+        suite(AnySessionStore::File(store)).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Needs a NATS server with JetStream, e.g. `NATS_URL=localhost:4222`
+    #[tokio::test]
+    async fn nats_store() {
+        let Ok(url) = std::env::var("NATS_URL") else {
+            eprintln!("NATS_URL is not set, skipping");
+            return;
+        };
+        let js = jetstream::new(async_nats::connect(url).await.unwrap());
+        let bucket = format!("sessions-test-{:x}", random::<u64>());
+        let kv = js
+            .create_key_value(jetstream::kv::Config {
+                bucket: bucket.clone(),
+                history: 1,
+                ..Default::default()
+            })
             .await
-            .map_err(|e| Error::Backend(e.to_string()))
+            .unwrap();
+        for codec in [RecordCodec::new(false, &[]), RecordCodec::new(true, &[(1, [7; 32])])] {
+            let store = NatsSessionStore::new(kv.clone(), codec);
+            suite(AnySessionStore::Nats(Box::new(store))).await;
+        }
+
+        // Two instances saving the same session keep each other's values
+        let first = NatsSessionStore::new(kv.clone(), RecordCodec::new(false, &[]));
+        let second = NatsSessionStore::new(kv.clone(), RecordCodec::new(false, &[]));
+        let mut session = record(Duration::hours(1));
+        first.create(&mut session).await.unwrap();
+        let mut from_first = first.load(&session.id).await.unwrap().unwrap();
+        let mut from_second = second.load(&session.id).await.unwrap().unwrap();
+        from_second.data.insert("second".to_owned(), serde_json::json!(2));
+        second.save(&from_second).await.unwrap();
+        from_first.data.insert("first".to_owned(), serde_json::json!(1));
+        first.save(&from_first).await.unwrap();
+        let stored = second.load(&session.id).await.unwrap().unwrap();
+        assert_eq!(stored.data["first"], 1);
+        assert_eq!(stored.data["second"], 2);
+
+        // Nor do they bring back values the other removed
+        let mut from_first = first.load(&session.id).await.unwrap().unwrap();
+        let mut from_second = second.load(&session.id).await.unwrap().unwrap();
+        from_second.data.remove("key");
+        second.save(&from_second).await.unwrap();
+        from_first.data.insert("third".to_owned(), serde_json::json!(3));
+        first.save(&from_first).await.unwrap();
+        let stored = second.load(&session.id).await.unwrap().unwrap();
+        assert!(!stored.data.contains_key("key"));
+        assert_eq!(stored.data["third"], 3);
+        js.delete_key_value(bucket).await.unwrap();
     }
 }
@@ -0,0 +1,226 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use rand::random;
+use std::{
+    fmt,
+    io::{Read, Write},
+    time::SystemTime,
+};
+use thiserror::Error;
+
+/// First byte of every encoded value. Plain json values from before the
+/// envelope start with `{` and are still read as is, unless encryption is
+/// required.
+const ENVELOPE_VERSION: u8 = 1;
+const FLAG_COMPRESSED: u8 = 1;
+const FLAG_ENCRYPTED: u8 = 1 << 1;
+const NONCE_LEN: usize = 12;
+/// Sessions are tiny, anything bigger than this isn't one
+const MAX_DECOMPRESSED_LEN: u64 = 1 << 20;
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    /// Most likely encrypted with a key that has been rotated out since
+    #[error("Unknown encryption key {0}")]
+    UnknownKey(u8),
+    /// Stored before encryption was enabled, or planted by someone without
+    /// the keys
+    #[error("Unencrypted value")]
+    Unencrypted,
+    #[error("Corrupted value: {0}")]
+    Corrupted(&'static str),
+    #[error("Compression failed: {0}")]
+    Compression(#[from] std::io::Error),
+}
+
+/// Turns serialized session records into what's stored in the bucket and back:
+/// optionally compressed and encrypted, wrapped into a small envelope
+///
+/// Values are encrypted with the first of the keys and decrypted with the one
+/// whose id they carry, so a new key can be put in front of the old ones and
+/// the old ones dropped once no sessions use them.
+///
+/// Once there are keys, unencrypted values are only read until the deadline
+/// set with [`RecordCodec::accept_unencrypted_until`], if any.
+#[derive(Clone, Default)]
+pub struct RecordCodec {
+    compress: bool,
+    keys: Vec<(u8, ChaCha20Poly1305)>,
+    unencrypted_deadline: Option<SystemTime>,
+}
+
+impl fmt::Debug for RecordCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordCodec")
+            .field("compress", &self.compress)
+            .field("key_ids", &self.keys.iter().map(|(id, _)| id).collect::<Vec<_>>())
+            .field("unencrypted_deadline", &self.unencrypted_deadline)
+            .finish()
+    }
+}
+
+impl RecordCodec {
+    pub fn new(compress: bool, keys: &[(u8, [u8; 32])]) -> Self {
+        Self {
+            compress,
+            keys: keys
+                .iter()
+                .map(|(id, key)| (*id, ChaCha20Poly1305::new(Key::from_slice(key))))
+                .collect(),
+            unencrypted_deadline: None,
+        }
+    }
+
+    /// Keeps reading the values stored before encryption was enabled for a
+    /// while, so their sessions survive the migration
+    pub fn accept_unencrypted_until(mut self, deadline: Option<SystemTime>) -> Self {
+        self.unencrypted_deadline = deadline;
+        self
+    }
+
+    fn check_unencrypted(&self) -> Result<(), CodecError> {
+        let accepted = self.keys.is_empty()
+            || self
+                .unencrypted_deadline
+                .is_some_and(|deadline| SystemTime::now() < deadline);
+        if accepted {
+            Ok(())
+        } else {
+            Err(CodecError::Unencrypted)
+        }
+    }
+
+    /// `context` is authenticated along with the value, so it can't be moved
+    /// to another key of the bucket
+    pub fn encode(&self, context: &str, value: Vec<u8>) -> Result<Vec<u8>, CodecError> {
+        if !self.compress && self.keys.is_empty() {
+            return Ok(value);
+        }
+        let mut header = vec![ENVELOPE_VERSION, 0];
+        let mut payload = value;
+        if self.compress {
+            header[1] |= FLAG_COMPRESSED;
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&payload)?;
+            payload = encoder.finish()?;
+        }
+        if let Some((id, cipher)) = self.keys.first() {
+            header[1] |= FLAG_ENCRYPTED;
+            header.push(*id);
+            let nonce: [u8; NONCE_LEN] = random();
+            let aad = [&header[..], context.as_bytes()].concat();
+            payload = cipher
+                .encrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: &payload,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| CodecError::Corrupted("encryption failed"))?;
+            header.extend_from_slice(&nonce);
+        }
+        header.extend_from_slice(&payload);
+        Ok(header)
+    }
+
+    pub fn decode(&self, context: &str, value: &[u8]) -> Result<Vec<u8>, CodecError> {
+        match value.first() {
+            Some(b'{') => {
+                self.check_unencrypted()?;
+                return Ok(value.to_vec());
+            }
+            Some(&ENVELOPE_VERSION) => {}
+            _ => return Err(CodecError::Corrupted("unknown envelope")),
+        }
+        let &flags = value.get(1).ok_or(CodecError::Corrupted("truncated header"))?;
+        if flags & FLAG_ENCRYPTED == 0 {
+            self.check_unencrypted()?;
+        }
+        let mut payload = value[2..].to_vec();
+        if flags & FLAG_ENCRYPTED != 0 {
+            let &id = payload.first().ok_or(CodecError::Corrupted("truncated header"))?;
+            let (_, cipher) = self
+                .keys
+                .iter()
+                .find(|(key_id, _)| *key_id == id)
+                .ok_or(CodecError::UnknownKey(id))?;
+            if payload.len() < 1 + NONCE_LEN {
+                return Err(CodecError::Corrupted("truncated nonce"));
+            }
+            let (nonce, ciphertext) = payload[1..].split_at(NONCE_LEN);
+            let aad = [&value[..3], context.as_bytes()].concat();
+            payload = cipher
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| CodecError::Corrupted("authentication failed"))?;
+        }
+        if flags & FLAG_COMPRESSED != 0 {
+            let mut decompressed = Vec::new();
+            DeflateDecoder::new(&payload[..])
+                .take(MAX_DECOMPRESSED_LEN)
+                .read_to_end(&mut decompressed)?;
+            payload = decompressed;
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const JSON: &[u8] = br#"{"id":1}"#;
+
+    fn encrypted() -> RecordCodec {
+        RecordCodec::new(false, &[(1, [7; 32])])
+    }
+
+    #[test]
+    fn round_trips() {
+        for codec in [
+            RecordCodec::new(false, &[]),
+            RecordCodec::new(true, &[]),
+            encrypted(),
+            RecordCodec::new(true, &[(1, [7; 32])]),
+        ] {
+            let value = codec.encode("key", JSON.to_vec()).unwrap();
+            assert_eq!(codec.decode("key", &value).unwrap(), JSON);
+        }
+    }
+
+    #[test]
+    fn encrypted_value_is_bound_to_its_key() {
+        let codec = encrypted();
+        let value = codec.encode("key", JSON.to_vec()).unwrap();
+        assert!(matches!(codec.decode("other", &value), Err(CodecError::Corrupted(_))));
+        let rotated = RecordCodec::new(false, &[(2, [8; 32])]);
+        assert!(matches!(rotated.decode("key", &value), Err(CodecError::UnknownKey(1))));
+    }
+
+    #[test]
+    fn unencrypted_rejected_once_keys_are_set() {
+        let compressed = RecordCodec::new(true, &[]).encode("key", JSON.to_vec()).unwrap();
+        let codec = encrypted();
+        for value in [JSON, &compressed[..]] {
+            assert!(matches!(codec.decode("key", value), Err(CodecError::Unencrypted)));
+        }
+
+        let expired = encrypted().accept_unencrypted_until(Some(SystemTime::now() - Duration::from_secs(1)));
+        assert!(matches!(expired.decode("key", JSON), Err(CodecError::Unencrypted)));
+
+        let migrating = encrypted().accept_unencrypted_until(Some(SystemTime::now() + Duration::from_secs(60)));
+        for value in [JSON, &compressed[..]] {
+            assert_eq!(migrating.decode("key", value).unwrap(), JSON);
+        }
+    }
+}
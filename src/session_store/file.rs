@@ -0,0 +1,174 @@
+use async_trait::async_trait;
+use rand::random;
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use time::OffsetDateTime;
+use tokio::fs;
+use tower_sessions::{
+    SessionStore,
+    session::{Id, Record},
+    session_store::{Error, ExpiredDeletion, Result},
+};
+
+fn serialize(record: &Record) -> Result<Vec<u8>> {
+    serde_json::to_vec(record).map_err(|e| Error::Encode(e.to_string()))
+}
+
+fn backend_error(e: std::io::Error) -> Error {
+    Error::Backend(e.to_string())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    path.with_extension(format!("{:x}.tmp", random::<u32>()))
+}
+
+/// Like writing to a temporary file and renaming it into place, except that
+/// it returns `false` instead of replacing an existing file. Linking a fully
+/// written file fails if the target exists, so exactly one writer wins and
+/// readers never see a partial file.
+async fn write_new(path: &Path, content: &[u8]) -> std::io::Result<bool> {
+    let tmp = temp_path(path);
+    fs::write(&tmp, content).await?;
+    let linked = fs::hard_link(&tmp, path).await;
+    let _ = fs::remove_file(&tmp).await;
+    match linked {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Stores every session as a separate json file in a directory
+#[derive(Debug, Clone)]
+pub struct FileSessionStore {
+    dir: PathBuf,
+}
+
+impl FileSessionStore {
+    pub async fn new(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir).await?;
+        Ok(Self { dir })
+    }
+
+    /// Checks that the directory is still there
+    pub async fn ping(&self) -> Result<()> {
+        fs::metadata(&self.dir).await.map_err(backend_error)?;
+        Ok(())
+    }
+
+    fn path(&self, id: &Id) -> PathBuf {
+        self.dir.join(format!("{:X}.json", id.0))
+    }
+
+    fn identity_path(&self, id: &Id) -> PathBuf {
+        self.dir.join(format!("{:X}.identity.json", id.0))
+    }
+
+    pub async fn create_identity(&self, session_id: &Id, identity: Vec<u8>) -> Result<Vec<u8>> {
+        let path = self.identity_path(session_id);
+        if write_new(&path, &identity).await.map_err(backend_error)? {
+            return Ok(identity);
+        }
+        fs::read(&path).await.map_err(backend_error)
+    }
+
+    /// Reads the record, `None` if there is none or it can't be decoded
+    async fn read_record(&self, path: &Path) -> Result<Option<Record>> {
+        let record = match fs::read(path).await {
+            Ok(r) => r,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(backend_error(e)),
+        };
+        let record = serde_json::from_slice(&record).map_err(|e| Error::Decode(e.to_string()))?;
+        Ok(Some(record))
+    }
+}
+
+fn is_expired(record: &Record) -> bool {
+    record.expiry_date <= OffsetDateTime::now_utc()
+}
+
+#[async_trait]
+impl SessionStore for FileSessionStore {
+    async fn create(&self, session_record: &mut Record) -> Result<()> {
+        // The id is part of the record, so it's serialized anew on collisions
+        while !write_new(&self.path(&session_record.id), &serialize(session_record)?)
+            .await
+            .map_err(backend_error)?
+        {
+            tracing::warn!("Collision on record key {}", session_record.id.0);
+            session_record.id.0 = random();
+        }
+        Ok(())
+    }
+
+    async fn save(&self, session_record: &Record) -> Result<()> {
+        // Write to a temporary file first so that readers never see a half-written record
+        let path = self.path(&session_record.id);
+        let tmp = temp_path(&path);
+        fs::write(&tmp, serialize(session_record)?)
+            .await
+            .map_err(backend_error)?;
+        fs::rename(&tmp, &path).await.map_err(backend_error)
+    }
+
+    async fn load(&self, session_id: &Id) -> Result<Option<Record>> {
+        match self.read_record(&self.path(session_id)).await? {
+            Some(record) if is_expired(&record) => {
+                self.delete(session_id).await?;
+                Ok(None)
+            }
+            record => Ok(record),
+        }
+    }
+
+    async fn delete(&self, session_id: &Id) -> Result<()> {
+        for path in [self.identity_path(session_id), self.path(session_id)] {
+            match fs::remove_file(path).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(backend_error(e))?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for FileSessionStore {
+    async fn delete_expired(&self) -> Result<()> {
+        let mut entries = fs::read_dir(&self.dir).await.map_err(backend_error)?;
+        let mut purged = 0;
+        while let Some(entry) = entries.next_entry().await.map_err(backend_error)? {
+            let file_name = entry.file_name();
+            let Some(stem) = file_name.to_str().and_then(|name| name.strip_suffix(".json")) else {
+                continue;
+            };
+            // Identities go away with their session, on their own only once
+            // the session is gone already
+            let (hex, is_identity) = match stem.strip_suffix(".identity") {
+                Some(hex) => (hex, true),
+                None => (stem, false),
+            };
+            let Ok(id) = u128::from_str_radix(hex, 16).map(|id| Id(id as i128)) else {
+                continue;
+            };
+            let expired = match self.read_record(&self.path(&id)).await {
+                Ok(Some(record)) => !is_identity && is_expired(&record),
+                Ok(None) => is_identity,
+                Err(e) => {
+                    tracing::warn!("Skipping undecodable session {hex}: {e}");
+                    continue;
+                }
+            };
+            if expired {
+                self.delete(&id).await?;
+                purged += 1;
+            }
+        }
+        tracing::debug!("Purged {purged} expired sessions");
+        Ok(())
+    }
+}
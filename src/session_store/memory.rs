@@ -0,0 +1,90 @@
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use time::OffsetDateTime;
+use tower_sessions::{
+    SessionStore,
+    session::{Id, Record},
+    session_store::{ExpiredDeletion, Result},
+};
+
+#[derive(Debug, Default)]
+struct Sessions {
+    records: HashMap<Id, Record>,
+    /// See [`super::AnySessionStore::create_identity`], they go away with
+    /// their session
+    identities: HashMap<Id, Vec<u8>>,
+}
+
+impl Sessions {
+    fn remove(&mut self, session_id: &Id) {
+        self.records.remove(session_id);
+        self.identities.remove(session_id);
+    }
+}
+
+fn is_expired(record: &Record) -> bool {
+    record.expiry_date <= OffsetDateTime::now_utc()
+}
+
+/// Keeps the sessions in memory, they are lost on restart
+#[derive(Debug, Clone, Default)]
+pub struct MemorySessionStore(Arc<Mutex<Sessions>>);
+
+impl MemorySessionStore {
+    pub async fn create_identity(&self, session_id: &Id, identity: Vec<u8>) -> Result<Vec<u8>> {
+        let mut sessions = self.0.lock().unwrap();
+        Ok(sessions.identities.entry(*session_id).or_insert(identity).clone())
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn create(&self, session_record: &mut Record) -> Result<()> {
+        let mut sessions = self.0.lock().unwrap();
+        while sessions.records.contains_key(&session_record.id) {
+            tracing::warn!("Collision on record key {}", session_record.id.0);
+            session_record.id = Id::default();
+        }
+        sessions.records.insert(session_record.id, session_record.clone());
+        Ok(())
+    }
+
+    async fn save(&self, session_record: &Record) -> Result<()> {
+        let mut sessions = self.0.lock().unwrap();
+        sessions.records.insert(session_record.id, session_record.clone());
+        Ok(())
+    }
+
+    async fn load(&self, session_id: &Id) -> Result<Option<Record>> {
+        let mut sessions = self.0.lock().unwrap();
+        match sessions.records.get(session_id) {
+            Some(record) if is_expired(record) => {
+                sessions.remove(session_id);
+                Ok(None)
+            }
+            record => Ok(record.cloned()),
+        }
+    }
+
+    async fn delete(&self, session_id: &Id) -> Result<()> {
+        self.0.lock().unwrap().remove(session_id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for MemorySessionStore {
+    async fn delete_expired(&self) -> Result<()> {
+        let mut sessions = self.0.lock().unwrap();
+        let Sessions {
+            records,
+            identities,
+        } = &mut *sessions;
+        records.retain(|_, record| !is_expired(record));
+        identities.retain(|session_id, _| records.contains_key(session_id));
+        Ok(())
+    }
+}
@@ -0,0 +1,458 @@
+use async_nats::jetstream::kv::{CreateErrorKind, Operation, Store, UpdateErrorKind};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use rand::random;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use tower_sessions::{
+    session::{Id, Record},
+    session_store::{Error, ExpiredDeletion, Result},
+    SessionStore,
+};
+
+use super::codec::{CodecError, RecordCodec};
+
+/// Revisions of sessions that weren't loaded or saved for this long are
+/// forgotten, the next save merges into whatever is stored then
+const IDLE_REVISION_TTL: Duration = Duration::from_secs(10 * 60);
+/// How many times a save is merged into a concurrently modified session
+const MAX_SAVE_ATTEMPTS: usize = 5;
+
+type Data = HashMap<String, Value>;
+
+/// A session as this instance last loaded or saved it
+#[derive(Debug)]
+struct Tracked {
+    revision: u64,
+    data: Data,
+    touched: Instant,
+}
+
+fn to_nats_key(v: &Id) -> String {
+    format!("{:X}", v.0)
+}
+
+fn to_identity_key(v: &Id) -> String {
+    identity_key_of(&to_nats_key(v))
+}
+
+fn identity_key_of(key: &str) -> String {
+    format!("{key}.identity")
+}
+
+fn serialize(record: &Record) -> Result<Vec<u8>> {
+    serde_json::to_vec(record).map_err(|e| Error::Encode(e.to_string()))
+}
+
+/// Applies what the request changed since `loaded` to the stored record, so
+/// what the concurrent request stored or removed under other keys survives.
+/// Without knowing what was loaded nothing can be told removed, the values
+/// being saved are applied over the stored ones.
+fn merge(ours: &Record, loaded: Option<&Data>, mut stored: Record) -> Record {
+    if let Some(loaded) = loaded {
+        for key in loaded.keys().filter(|key| !ours.data.contains_key(*key)) {
+            stored.data.remove(key);
+        }
+    }
+    for (key, value) in &ours.data {
+        if loaded.and_then(|loaded| loaded.get(key)) != Some(value) {
+            stored.data.insert(key.clone(), value.clone());
+        }
+    }
+    Record {
+        id: ours.id,
+        data: stored.data,
+        expiry_date: ours.expiry_date,
+    }
+}
+
+fn deserialize(value: &[u8]) -> Result<Record> {
+    serde_json::from_slice(value).map_err(|e| Error::Decode(e.to_string()))
+}
+
+fn is_expired(record: &Record) -> bool {
+    record.expiry_date <= OffsetDateTime::now_utc()
+}
+
+fn backend_error(op: &'static str, e: impl ToString) -> Error {
+    metrics::counter!("session_store_errors_total", "op" => op).increment(1);
+    Error::Backend(e.to_string())
+}
+
+#[derive(Debug, Clone)]
+pub struct NatsSessionStore {
+    client: Store,
+    codec: RecordCodec,
+    /// Latest revision of every session this instance loaded or saved, saves
+    /// expect it to be the stored one and merge what changed since otherwise
+    revisions: Arc<Mutex<HashMap<Id, Tracked>>>,
+}
+
+impl NatsSessionStore {
+    pub fn new(client: Store, codec: RecordCodec) -> Self {
+        Self {
+            client,
+            codec,
+            revisions: Default::default(),
+        }
+    }
+
+    fn encode(&self, key: &str, value: Vec<u8>) -> Result<Vec<u8>> {
+        self.codec
+            .encode(key, value)
+            .map_err(|e| Error::Encode(e.to_string()))
+    }
+
+    /// Values encrypted with a key that's no longer configured can't be read
+    /// by anyone anymore and unencrypted ones aren't trusted once encryption
+    /// is required, they are as good as missing
+    fn decode(&self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.codec.decode(key, value) {
+            Ok(value) => Ok(Some(value)),
+            Err(e @ (CodecError::UnknownKey(_) | CodecError::Unencrypted)) => {
+                tracing::warn!("Ignoring session value {key}: {e}");
+                Ok(None)
+            }
+            Err(e) => Err(Error::Decode(e.to_string())),
+        }
+    }
+
+    fn decode_record(&self, key: &str, value: &[u8]) -> Result<Option<Record>> {
+        self.decode(key, value)?
+            .map(|value| deserialize(&value))
+            .transpose()
+    }
+
+    /// Replaces the revision of the session, there is one per session at most
+    fn track_revision(&self, record: &Record, revision: u64) {
+        let tracked = Tracked {
+            revision,
+            data: record.data.clone(),
+            touched: Instant::now(),
+        };
+        self.revisions.lock().unwrap().insert(record.id, tracked);
+    }
+
+    /// The revision to expect and the data it holds
+    fn tracked_revision(&self, session_id: &Id) -> Option<(u64, Data)> {
+        let revisions = self.revisions.lock().unwrap();
+        let tracked = revisions.get(session_id)?;
+        (tracked.touched.elapsed() < IDLE_REVISION_TTL)
+            .then(|| (tracked.revision, tracked.data.clone()))
+    }
+
+    fn forget_revision(&self, session_id: &Id) {
+        self.revisions.lock().unwrap().remove(session_id);
+    }
+
+    /// Drops the revisions of idle sessions
+    fn sweep_revisions(&self) {
+        let mut revisions = self.revisions.lock().unwrap();
+        revisions.retain(|_, tracked| tracked.touched.elapsed() < IDLE_REVISION_TTL);
+    }
+
+    /// The stored record and its revision, `None` if the session is gone
+    async fn load_entry(&self, key: &str) -> Result<Option<(Record, u64)>> {
+        let entry = self
+            .client
+            .entry(key)
+            .await
+            .map_err(|e| backend_error("load", e))?;
+        let entry = match entry {
+            Some(entry) if entry.operation == Operation::Put => entry,
+            _ => return Ok(None),
+        };
+        let record = self.decode_record(key, &entry.value)?;
+        Ok(record.map(|record| (record, entry.revision)))
+    }
+
+    /// Removes the session along with everything stored next to it
+    async fn purge(&self, session_id: &Id) -> Result<()> {
+        self.forget_revision(session_id);
+        self.purge_key(&to_nats_key(session_id)).await
+    }
+
+    async fn purge_key(&self, key: &str) -> Result<()> {
+        for key in [identity_key_of(key), key.to_owned()] {
+            self.client
+                .purge(key)
+                .await
+                .map_err(|e| backend_error("purge", e))?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the bucket is reachable
+    pub async fn ping(&self) -> Result<()> {
+        self.client
+            .status()
+            .await
+            .map_err(|e| backend_error("status", e))?;
+        Ok(())
+    }
+
+    pub async fn create_identity(&self, session_id: &Id, identity: Vec<u8>) -> Result<Vec<u8>> {
+        let key = to_identity_key(session_id);
+        let value = self.encode(&key, identity.clone())?;
+        loop {
+            match self.client.create(&key, value.clone().into()).await {
+                Ok(_) => return Ok(identity),
+                Err(e) if e.kind() == CreateErrorKind::AlreadyExists => {}
+                Err(e) => return Err(backend_error("create_identity", e)),
+            }
+            // Someone else was faster, unless their identity got deleted since
+            let stored = self
+                .client
+                .get(&key)
+                .await
+                .map_err(|e| backend_error("load_identity", e))?;
+            let Some(stored) = stored else {
+                continue;
+            };
+            match self.decode(&key, &stored)? {
+                Some(stored) => return Ok(stored),
+                None => self
+                    .client
+                    .purge(&key)
+                    .await
+                    .map_err(|e| backend_error("purge", e))?,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for NatsSessionStore {
+    async fn create(&self, session_record: &mut Record) -> Result<()> {
+        loop {
+            let key = to_nats_key(&session_record.id);
+            let value = self.encode(&key, serialize(session_record)?)?;
+            let result = self.client.create(key, value.into()).await;
+            match result {
+                Ok(revision) => {
+                    self.track_revision(session_record, revision);
+                    return Ok(());
+                }
+                Err(e) if e.kind() == CreateErrorKind::AlreadyExists => {
+                    tracing::warn!("Collision on record key {}", session_record.id.0);
+                }
+                Err(e) => {
+                    return Err(backend_error("create", e));
+                }
+            }
+            session_record.id.0 = random();
+        }
+    }
+
+    /// Another request of the same session may have saved since this one
+    /// loaded it, the record is then merged into the stored one
+    async fn save(&self, session_record: &Record) -> Result<()> {
+        let key = to_nats_key(&session_record.id);
+        let mut record = session_record.clone();
+        let tracked = self.tracked_revision(&record.id);
+        let loaded = tracked.as_ref().map(|(_, data)| data);
+        let mut expected = tracked.as_ref().map(|&(revision, _)| revision);
+        for _ in 0..MAX_SAVE_ATTEMPTS {
+            let value = self.encode(&key, serialize(&record)?)?;
+            let result = match expected {
+                Some(revision) => self.client.update(&key, value.into(), revision).await,
+                None => {
+                    let Some((stored, revision)) = self.load_entry(&key).await? else {
+                        // Never stored, or deleted meanwhile and not to be brought back
+                        return Ok(());
+                    };
+                    record = merge(session_record, loaded, stored);
+                    expected = Some(revision);
+                    continue;
+                }
+            };
+            match result {
+                Ok(revision) => {
+                    self.track_revision(&record, revision);
+                    return Ok(());
+                }
+                Err(e) if e.kind() == UpdateErrorKind::WrongLastRevision => {
+                    metrics::counter!("session_store_conflicts_total").increment(1);
+                    tracing::debug!("Session {} was modified concurrently, merging", record.id);
+                    expected = None;
+                }
+                Err(e) => return Err(backend_error("save", e)),
+            }
+        }
+        Err(backend_error(
+            "save",
+            format!("Session {} keeps being modified concurrently", record.id),
+        ))
+    }
+
+    async fn load(&self, session_id: &Id) -> Result<Option<Record>> {
+        let Some((record, revision)) = self.load_entry(&to_nats_key(session_id)).await? else {
+            return Ok(None);
+        };
+        if is_expired(&record) {
+            self.purge(session_id).await?;
+            return Ok(None);
+        }
+        self.track_revision(&record, revision);
+        Ok(Some(record))
+    }
+
+    async fn delete(&self, session_id: &Id) -> Result<()> {
+        self.forget_revision(session_id);
+        self.client
+            .delete(to_identity_key(session_id))
+            .await
+            .map_err(|e| backend_error("delete", e))?;
+        self.client
+            .delete(to_nats_key(session_id))
+            .await
+            .map_err(|e| backend_error("delete", e))
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for NatsSessionStore {
+    async fn delete_expired(&self) -> Result<()> {
+        self.sweep_revisions();
+        let keys: Vec<String> = self
+            .client
+            .keys()
+            .await
+            .map_err(|e| backend_error("keys", e))?
+            .try_collect()
+            .await
+            .map_err(|e| backend_error("keys", e))?;
+        let mut purged = 0;
+        let sessions: HashSet<&str> = keys
+            .iter()
+            .filter(|key| !key.contains('.'))
+            .map(String::as_str)
+            .collect();
+        // Identities live under `<session>.identity` and go away with their
+        // session, those left behind by a failed purge or delete go now
+        for key in &keys {
+            let Some(session_key) = key.strip_suffix(".identity") else {
+                continue;
+            };
+            if sessions.contains(session_key) {
+                continue;
+            }
+            // The session may have been created after the keys were listed
+            let session = self
+                .client
+                .get(session_key)
+                .await
+                .map_err(|e| backend_error("load", e))?;
+            if session.is_none() {
+                self.client
+                    .purge(key)
+                    .await
+                    .map_err(|e| backend_error("purge", e))?;
+                purged += 1;
+            }
+        }
+        for key in sessions {
+            let Some(value) = self
+                .client
+                .get(key)
+                .await
+                .map_err(|e| backend_error("load", e))?
+            else {
+                continue;
+            };
+            let record = match self.decode_record(key, &value) {
+                Ok(Some(record)) => record,
+                Ok(None) => {
+                    self.purge_key(key).await?;
+                    purged += 1;
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!("Skipping undecodable session {key}: {e}");
+                    continue;
+                }
+            };
+            if is_expired(&record) {
+                self.purge(&record.id).await?;
+                purged += 1;
+            }
+        }
+        tracing::debug!("Purged {purged} expired sessions");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge_keeps_the_values_of_both() {
+        let stored = Record {
+            id: Id::default(),
+            data: [
+                ("theirs".to_owned(), json!(1)),
+                ("both".to_owned(), json!("theirs")),
+            ]
+            .into(),
+            expiry_date: OffsetDateTime::now_utc(),
+        };
+        let ours = Record {
+            id: stored.id,
+            data: [
+                ("ours".to_owned(), json!(2)),
+                ("both".to_owned(), json!("ours")),
+            ]
+            .into(),
+            expiry_date: OffsetDateTime::now_utc() + time::Duration::hours(1),
+        };
+        let merged = merge(&ours, None, stored);
+        assert_eq!(merged.data["theirs"], 1);
+        assert_eq!(merged.data["ours"], 2);
+        assert_eq!(merged.data["both"], "ours");
+        assert_eq!(merged.expiry_date, ours.expiry_date);
+    }
+
+    #[test]
+    fn merge_applies_only_what_changed_since_loaded() {
+        let loaded: Data = [
+            ("identity".to_owned(), json!("old")),
+            ("passcodes".to_owned(), json!(["a"])),
+            ("name".to_owned(), json!("Ada")),
+        ]
+        .into();
+        let stored = Record {
+            id: Id::default(),
+            data: [
+                ("identity".to_owned(), json!("old")),
+                ("passcodes".to_owned(), json!(["a", "b"])),
+                ("name".to_owned(), json!("Ada")),
+                ("theirs".to_owned(), json!(1)),
+            ]
+            .into(),
+            expiry_date: OffsetDateTime::now_utc(),
+        };
+        // E.g. a logout removing the identity while another request
+        // remembered a passcode
+        let ours = Record {
+            id: stored.id,
+            data: [
+                ("passcodes".to_owned(), json!(["a"])),
+                ("name".to_owned(), json!("Grace")),
+            ]
+            .into(),
+            expiry_date: OffsetDateTime::now_utc(),
+        };
+        let merged = merge(&ours, Some(&loaded), stored);
+        assert!(!merged.data.contains_key("identity"));
+        assert_eq!(merged.data["passcodes"], json!(["a", "b"]));
+        assert_eq!(merged.data["name"], "Grace");
+        assert_eq!(merged.data["theirs"], 1);
+    }
+}
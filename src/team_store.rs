@@ -0,0 +1,388 @@
+use async_nats::jetstream::{
+    consumer::{DeliverPolicy, pull},
+    kv::{self, CreateErrorKind, UpdateErrorKind},
+};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize, de::DeserializeOwned, de::IgnoredAny};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+use tokio::{fs, sync::Mutex as AsyncMutex};
+
+use crate::components::poker::room::api::{Round, RoomSettings, TeamDetails, TeamSummary};
+
+#[derive(Debug, Error)]
+pub enum TeamStoreError {
+    #[error("Team store failed: {0}")]
+    Backend(String),
+    #[error("Can't decode team {slug}: {source}")]
+    Decode {
+        slug: String,
+        source: serde_json::Error,
+    },
+}
+
+type Result<T> = std::result::Result<T, TeamStoreError>;
+
+fn backend_error(e: impl ToString) -> TeamStoreError {
+    TeamStoreError::Backend(e.to_string())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TeamMember {
+    #[serde(with = "crate::uid::uid_hex")]
+    pub uid: u128,
+    pub name: String,
+}
+
+/// A team's recurring room, kept across restarts. The room is addressed by
+/// the team's slug and starts with the team's settings, the first member
+/// hosts it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Team {
+    pub slug: String,
+    pub members: Vec<TeamMember>,
+    pub settings: RoomSettings,
+    /// Everyone but the members has to enter it to join
+    pub passcode: Option<String>,
+    /// Oldest round first
+    #[serde(default)]
+    pub history: Vec<Round>,
+}
+
+impl Team {
+    pub fn is_member(&self, uid: u128) -> bool {
+        self.members.iter().any(|member| member.uid == uid)
+    }
+
+    /// Returns whether the member is new to the team
+    pub fn add_member(&mut self, uid: u128, name: String) -> bool {
+        if self.is_member(uid) {
+            return false;
+        }
+        self.members.push(TeamMember { uid, name });
+        true
+    }
+
+    /// Keeps only the `max_rounds` latest rounds
+    pub fn record_round(&mut self, round: Round, max_rounds: usize) {
+        self.history.push(round);
+        let excess = self.history.len().saturating_sub(max_rounds);
+        self.history.drain(..excess);
+    }
+
+    /// As shown to the player, who may be a member
+    pub fn details(self, uid: Option<u128>) -> TeamDetails {
+        TeamDetails {
+            is_member: uid.is_some_and(|uid| self.is_member(uid)),
+            members: self.members.into_iter().map(|member| member.name).collect(),
+            history: self.history.into_iter().rev().collect(),
+        }
+    }
+
+    fn summary(&self) -> TeamSummary {
+        TeamSummary {
+            slug: self.slug.clone(),
+            members: self.members.len(),
+            rounds: self.history.len(),
+        }
+    }
+}
+
+/// What listing a team takes, its rounds are only counted
+#[derive(Deserialize)]
+struct TeamListing {
+    slug: String,
+    members: Vec<TeamMember>,
+    #[serde(default)]
+    history: Vec<IgnoredAny>,
+}
+
+impl TeamListing {
+    fn summary(self) -> TeamSummary {
+        TeamSummary {
+            slug: self.slug,
+            members: self.members.len(),
+            rounds: self.history.len(),
+        }
+    }
+}
+
+fn encode(team: &Team) -> Result<Vec<u8>> {
+    serde_json::to_vec(team).map_err(backend_error)
+}
+
+fn decode<T: DeserializeOwned>(slug: &str, value: &[u8]) -> Result<T> {
+    serde_json::from_slice(value).map_err(|source| TeamStoreError::Decode {
+        slug: slug.to_owned(),
+        source,
+    })
+}
+
+/// Where the teams are kept, picked at startup next to the session store
+#[derive(Debug, Clone)]
+pub enum TeamStore {
+    Nats(Box<kv::Store>),
+    Memory(Arc<Mutex<HashMap<String, Team>>>),
+    /// A json file per team. Writes are serialized within the process, the
+    /// directory can't be shared between instances.
+    File {
+        dir: PathBuf,
+        lock: Arc<AsyncMutex<()>>,
+    },
+}
+
+impl Default for TeamStore {
+    fn default() -> Self {
+        Self::Memory(Default::default())
+    }
+}
+
+impl TeamStore {
+    pub async fn file(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir).await?;
+        Ok(Self::File {
+            dir,
+            lock: Default::default(),
+        })
+    }
+
+    fn path(dir: &Path, slug: &str) -> PathBuf {
+        dir.join(format!("{slug}.json"))
+    }
+
+    async fn read_file<T: DeserializeOwned>(path: &Path, slug: &str) -> Result<Option<T>> {
+        match fs::read(path).await {
+            Ok(value) => decode(slug, &value).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn write_file(path: &Path, team: &Team) -> Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, encode(team)?).await.map_err(backend_error)?;
+        fs::rename(&tmp, path).await.map_err(backend_error)
+    }
+
+    pub async fn get(&self, slug: &str) -> Result<Option<Team>> {
+        match self {
+            Self::Nats(store) => match store.get(slug).await.map_err(backend_error)? {
+                Some(value) => decode(slug, &value).map(Some),
+                None => Ok(None),
+            },
+            Self::Memory(teams) => Ok(teams.lock().unwrap().get(slug).cloned()),
+            Self::File { dir, .. } => Self::read_file(&Self::path(dir, slug), slug).await,
+        }
+    }
+
+    /// Returns `false` if there is a team with the slug already
+    pub async fn create(&self, team: &Team) -> Result<bool> {
+        match self {
+            Self::Nats(store) => match store.create(&team.slug, encode(team)?.into()).await {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == CreateErrorKind::AlreadyExists => Ok(false),
+                Err(e) => Err(backend_error(e)),
+            },
+            Self::Memory(teams) => {
+                let mut teams = teams.lock().unwrap();
+                if teams.contains_key(&team.slug) {
+                    return Ok(false);
+                }
+                teams.insert(team.slug.clone(), team.clone());
+                Ok(true)
+            }
+            Self::File { dir, lock } => {
+                let _lock = lock.lock().await;
+                let path = Self::path(dir, &team.slug);
+                if fs::try_exists(&path).await.map_err(backend_error)? {
+                    return Ok(false);
+                }
+                Self::write_file(&path, team).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Applies `change` to the team unless it's gone. `change` returns
+    /// whether it changed anything and may be called several times when
+    /// other instances update the team concurrently.
+    pub async fn update(&self, slug: &str, mut change: impl FnMut(&mut Team) -> bool) -> Result<()> {
+        match self {
+            Self::Nats(store) => loop {
+                let Some(entry) = store.entry(slug).await.map_err(backend_error)? else {
+                    return Ok(());
+                };
+                if entry.operation != kv::Operation::Put {
+                    return Ok(());
+                }
+                let mut team = decode(slug, &entry.value)?;
+                if !change(&mut team) {
+                    return Ok(());
+                }
+                match store.update(slug, encode(&team)?.into(), entry.revision).await {
+                    Ok(_) => return Ok(()),
+                    Err(e) if e.kind() == UpdateErrorKind::WrongLastRevision => continue,
+                    Err(e) => return Err(backend_error(e)),
+                }
+            },
+            Self::Memory(teams) => {
+                if let Some(team) = teams.lock().unwrap().get_mut(slug) {
+                    change(team);
+                }
+                Ok(())
+            }
+            Self::File { dir, lock } => {
+                let _lock = lock.lock().await;
+                let path = Self::path(dir, slug);
+                let Some(mut team) = Self::read_file(&path, slug).await? else {
+                    return Ok(());
+                };
+                if !change(&mut team) {
+                    return Ok(());
+                }
+                Self::write_file(&path, &team).await
+            }
+        }
+    }
+
+    /// Summaries of the teams the player is a member of, their history is
+    /// only counted, not decoded. Teams that can't be read are left out.
+    pub async fn summaries(&self, uid: u128) -> Result<Vec<TeamSummary>> {
+        let mut listings: Vec<Result<TeamListing>> = vec![];
+        match self {
+            Self::Nats(store) => {
+                // A single consumer delivers the latest value of every team,
+                // rather than a request per team
+                let consumer = store
+                    .stream
+                    .create_consumer(pull::OrderedConfig {
+                        filter_subject: format!("{}>", store.prefix),
+                        deliver_policy: DeliverPolicy::LastPerSubject,
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(backend_error)?;
+                let pending = consumer.cached_info().num_pending as usize;
+                let mut messages = consumer
+                    .messages()
+                    .await
+                    .map_err(backend_error)?
+                    .take(pending);
+                while let Some(message) = messages.try_next().await.map_err(backend_error)? {
+                    // Deleted and purged teams leave a marker behind
+                    let removed = message
+                        .headers
+                        .as_ref()
+                        .is_some_and(|headers| headers.get("KV-Operation").is_some());
+                    if removed {
+                        continue;
+                    }
+                    let slug = message.subject.trim_start_matches(store.prefix.as_str());
+                    listings.push(decode(slug, &message.payload));
+                }
+            }
+            Self::Memory(teams) => {
+                let teams = teams.lock().unwrap();
+                return Ok(teams
+                    .values()
+                    .filter(|team| team.is_member(uid))
+                    .map(Team::summary)
+                    .collect());
+            }
+            Self::File { dir, .. } => {
+                let mut entries = fs::read_dir(dir).await.map_err(backend_error)?;
+                while let Some(entry) = entries.next_entry().await.map_err(backend_error)? {
+                    let path = entry.path();
+                    if path.extension().is_none_or(|ext| ext != "json") {
+                        continue;
+                    }
+                    let Some(slug) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                        continue;
+                    };
+                    if let Some(listing) = Self::read_file(&path, slug).await.transpose() {
+                        listings.push(listing);
+                    }
+                }
+            }
+        }
+        Ok(listings
+            .into_iter()
+            .filter_map(|listing| {
+                listing
+                    .inspect_err(|e| tracing::warn!("Leaving team out of the listing: {e}"))
+                    .ok()
+            })
+            .filter(|listing| listing.members.iter().any(|member| member.uid == uid))
+            .map(TeamListing::summary)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_nats::jetstream;
+    use rand::random;
+
+    fn team(slug: &str, member: u128) -> Team {
+        Team {
+            slug: slug.to_owned(),
+            members: vec![TeamMember {
+                uid: member,
+                name: "name".to_owned(),
+            }],
+            settings: Default::default(),
+            passcode: None,
+            history: Vec::new(),
+        }
+    }
+
+    /// Needs a NATS server with JetStream, e.g. `NATS_URL=localhost:4222`
+    #[tokio::test]
+    async fn nats_summaries() {
+        let Ok(url) = std::env::var("NATS_URL") else {
+            eprintln!("NATS_URL is not set, skipping");
+            return;
+        };
+        let js = jetstream::new(async_nats::connect(url).await.unwrap());
+        let bucket = format!("teams-test-{:x}", random::<u64>());
+        let kv = js
+            .create_key_value(kv::Config {
+                bucket: bucket.clone(),
+                history: 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let store = TeamStore::Nats(Box::new(kv.clone()));
+        assert!(store.summaries(1).await.unwrap().is_empty());
+
+        store.create(&team("a", 1)).await.unwrap();
+        store.create(&team("b", 2)).await.unwrap();
+        store.create(&team("gone", 1)).await.unwrap();
+        kv.delete("gone").await.unwrap();
+        kv.put("broken", "{".into()).await.unwrap();
+        store
+            .update("a", |team| {
+                let round = Round {
+                    revealed_at: 0,
+                    votes: Vec::new(),
+                };
+                team.record_round(round, 10);
+                true
+            })
+            .await
+            .unwrap();
+
+        let summaries = store.summaries(1).await.unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!((summaries[0].slug.as_str(), summaries[0].rounds), ("a", 1));
+        js.delete_key_value(bucket).await.unwrap();
+    }
+}
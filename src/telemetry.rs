@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, layer::SubscriberExt, util::SubscriberInitExt,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter directives in the `RUST_LOG` syntax, e.g. `info,scrum_poker=debug`
+    pub filter: String,
+    pub format: LogFormat,
+    /// OTLP/HTTP collector to export spans to, e.g. `http://localhost:4318/v1/traces`.
+    /// Requires the `otlp` feature.
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "debug".to_owned(),
+            format: LogFormat::Text,
+            otlp_endpoint: None,
+        }
+    }
+}
+
+/// Flushes the exported spans when dropped
+#[must_use]
+pub struct TelemetryGuard {
+    #[cfg(feature = "otlp")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.tracer_provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush spans: {e}");
+        }
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+#[cfg(feature = "otlp")]
+fn otlp_layer(
+    endpoint: &str,
+) -> Result<(BoxedLayer, opentelemetry_sdk::trace::SdkTracerProvider), String> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        .boxed();
+    Ok((layer, provider))
+}
+
+/// Installs the global tracing subscriber
+pub fn init(config: &LogConfig) -> Result<TelemetryGuard, String> {
+    let filter = EnvFilter::try_new(&config.filter).map_err(|e| e.to_string())?;
+    let fmt_layer = match config.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+    #[cfg_attr(not(feature = "otlp"), allow(unused_mut))]
+    let mut layers: Vec<BoxedLayer> = vec![fmt_layer];
+
+    #[cfg_attr(not(feature = "otlp"), allow(unused_mut))]
+    let mut guard = TelemetryGuard {
+        #[cfg(feature = "otlp")]
+        tracer_provider: None,
+    };
+    match &config.otlp_endpoint {
+        None => {}
+        #[cfg(feature = "otlp")]
+        Some(endpoint) => {
+            let (layer, provider) = otlp_layer(endpoint)?;
+            layers.push(layer);
+            guard.tracer_provider = Some(provider);
+        }
+        #[cfg(not(feature = "otlp"))]
+        Some(_) => return Err("OTLP export requires the otlp feature".to_owned()),
+    }
+
+    tracing_subscriber::registry()
+        .with(layers.with_filter(filter))
+        .init();
+    Ok(guard)
+}
+
+#[cfg(all(test, feature = "otlp"))]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    /// Stands in for an OTLP/HTTP collector, hands over the body of every export
+    fn collector() -> (String, mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+                let _ = tx.send(body);
+            }
+        });
+        (endpoint, rx)
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    }
+
+    #[test]
+    fn spans_are_exported_to_the_collector() {
+        let (endpoint, exports) = collector();
+        let (layer, provider) = otlp_layer(&endpoint).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "server_fn",
+                server_fn = "place_bet",
+                room_id = "some-room",
+                uid = tracing::field::Empty,
+            );
+            span.record("uid", 42);
+            span.in_scope(|| tracing::info!("inside"));
+        });
+        provider.shutdown().unwrap();
+
+        let body = exports.recv_timeout(Duration::from_secs(10)).unwrap();
+        for expected in ["scrum-poker", "server_fn", "place_bet", "some-room", "uid"] {
+            assert!(contains(&body, expected), "{expected} wasn't exported");
+        }
+    }
+}
@@ -1,29 +1,311 @@
 use rand::random;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
 use tower_sessions::{Session, session::Error, session_store};
 
-const UID_KEY: &str = "UID";
+use crate::session_store::AnySessionStore;
 
-pub async fn get_or_create_uid(session: &Session) -> Result<u128, Error> {
-    let id = get_uid(session).await.transpose();
-    if let Some(id) = id {
-        return id;
+const IDENTITY_KEY: &str = "identity";
+/// Where the uid used to be kept as raw 16 bytes, before [`Identity`]
+const LEGACY_UID_KEY: &str = "UID";
+const IDENTITY_VERSION: u32 = 1;
+
+/// Per-player settings. New fields have to be `#[serde(default)]`, so
+/// identities stored before them keep loading.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preferences {}
+
+/// Single sign-on account the identity belongs to
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Account {
+    pub issuer: String,
+    pub subject: String,
+}
+
+impl Account {
+    /// The same account gets the same uid in every browser
+    pub fn uid(&self) -> u128 {
+        let hash = Sha256::new()
+            .chain_update(&self.issuer)
+            .chain_update([0])
+            .chain_update(&self.subject)
+            .finalize();
+        u128::from_le_bytes(hash[..16].try_into().expect("Sha256 to be longer than 16 bytes"))
+    }
+}
+
+/// Who the player is, kept in their session
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub version: u32,
+    /// Json numbers can't hold 128 bits, hence the string
+    #[serde(with = "uid_hex")]
+    pub uid: u128,
+    /// Name the player goes by in every room, a random one if not set
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(with = "time::serde::timestamp")]
+    pub created_at: OffsetDateTime,
+    #[serde(default)]
+    pub preferences: Preferences,
+    /// Anonymous players have none
+    #[serde(default)]
+    pub account: Option<Account>,
+}
+
+impl Identity {
+    pub fn new(uid: u128) -> Self {
+        Self {
+            version: IDENTITY_VERSION,
+            uid,
+            display_name: None,
+            // Stored in whole seconds, so a new identity equals its stored self
+            created_at: OffsetDateTime::now_utc()
+                .replace_nanosecond(0)
+                .expect("0 to be a valid nanosecond"),
+            preferences: Default::default(),
+            account: None,
+        }
+    }
+
+    pub fn for_account(account: Account, display_name: Option<String>) -> Self {
+        Self {
+            display_name,
+            account: Some(account.clone()),
+            ..Self::new(account.uid())
+        }
+    }
+
+    /// Decodes the raw bytes the uid used to be stored as. They were written
+    /// in native byte order but always read as little-endian, so that's the
+    /// uid the player has been known by.
+    fn from_legacy(bytes: Vec<u8>) -> Result<Self, Error> {
+        let bytes = bytes
+            .try_into()
+            .map_err(|v| decode_error(format!("Failed to deserialize legacy uid {v:?}")))?;
+        Ok(Self::new(u128::from_le_bytes(bytes)))
+    }
+}
+
+pub(crate) mod uid_hex {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(uid: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{uid:032x}"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        u128::from_str_radix(&s, 16).map_err(D::Error::custom)
+    }
+}
+
+fn decode_error(message: String) -> Error {
+    Error::Store(session_store::Error::Decode(message))
+}
+
+async fn save_identity(session: &Session, identity: &Identity) -> Result<(), Error> {
+    session.insert(IDENTITY_KEY, identity).await?;
+    session.save().await
+}
+
+/// Loads the identity, upgrading it from the older formats if needed
+pub async fn get_identity(session: &Session) -> Result<Option<Identity>, Error> {
+    if let Some(identity) = session.get::<Identity>(IDENTITY_KEY).await? {
+        if identity.version > IDENTITY_VERSION {
+            return Err(decode_error(format!(
+                "Unsupported identity version {}",
+                identity.version
+            )));
+        }
+        return Ok(Some(identity));
     }
-    let id: u128 = random();
-    // Potential data race here, but I think it's mostly ok
-    session.insert(UID_KEY, id.to_ne_bytes()).await?;
+    let Some(bytes) = session.get::<Vec<u8>>(LEGACY_UID_KEY).await? else {
+        return Ok(None);
+    };
+    let identity = Identity::from_legacy(bytes)?;
+    save_identity(session, &identity).await?;
+    session.remove_value(LEGACY_UID_KEY).await?;
     session.save().await?;
-    Ok(id)
+    tracing::debug!("Migrated legacy uid {}", identity.uid);
+    Ok(Some(identity))
+}
+
+/// Concurrent requests of the same session get the same identity, the store
+/// decides which of the freshly minted ones wins
+pub async fn get_or_create_identity(
+    session: &Session,
+    store: &AnySessionStore,
+) -> Result<Identity, Error> {
+    if let Some(identity) = get_identity(session).await? {
+        return Ok(identity);
+    }
+    // The store picks the identity under the session's id, so a new session
+    // is saved to get one first
+    if session.id().is_none() {
+        session.save().await?;
+    }
+    let session_id = session.id().expect("Saved session to have an id");
+    let identity = serde_json::to_vec(&Identity::new(random()))
+        .map_err(|e| Error::Store(session_store::Error::Encode(e.to_string())))?;
+    let identity = store
+        .create_identity(&session_id, identity)
+        .await
+        .map_err(Error::Store)?;
+    let identity: Identity = serde_json::from_slice(&identity)
+        .map_err(|e| decode_error(e.to_string()))?;
+    save_identity(session, &identity).await?;
+    Ok(identity)
 }
 
 pub async fn get_uid(session: &Session) -> Result<Option<u128>, Error> {
-    let v = session.get(UID_KEY).await?;
-    let v: Vec<u8> = match v {
-        None => return Ok(None),
-        Some(v) => v,
-    };
-    Ok(Some(u128::from_le_bytes(v.try_into().map_err(|v| {
-        Error::Store(session_store::Error::Decode(format!(
-            "Failed to deserialize {v:?}"
-        )))
-    })?)))
+    get_identity(session)
+        .await
+        .map(|identity| identity.map(|identity| identity.uid))
+}
+
+/// Puts another identity into the session, the session id is rotated as well
+/// so the old cookie can't be used to act under the new one
+pub async fn replace_identity(session: &Session, identity: &Identity) -> Result<(), Error> {
+    session.cycle_id().await?;
+    session.remove_value(LEGACY_UID_KEY).await?;
+    save_identity(session, identity).await
+}
+
+/// Gives the session a brand new anonymous identity
+pub async fn reset_uid(session: &Session) -> Result<u128, Error> {
+    let identity = Identity::new(random());
+    replace_identity(session, &identity).await?;
+    Ok(identity.uid)
+}
+
+/// Remembers the name the player goes by, so it follows them between rooms
+pub async fn set_display_name(
+    session: &Session,
+    store: &AnySessionStore,
+    name: String,
+) -> Result<(), Error> {
+    let mut identity = get_or_create_identity(session, store).await?;
+    identity.display_name = Some(name);
+    save_identity(session, &identity).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_store::MemorySessionStore;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn session() -> Session {
+        let store = AnySessionStore::Memory(MemorySessionStore::default());
+        Session::new(None, Arc::new(store), None)
+    }
+
+    #[test]
+    fn identity_round_trip() {
+        let anonymous = Identity::new(random());
+        let signed_in = Identity::for_account(
+            Account {
+                issuer: "https://issuer.example.com".to_owned(),
+                subject: "someone".to_owned(),
+            },
+            Some("Someone".to_owned()),
+        );
+        for identity in [anonymous, signed_in] {
+            let json = serde_json::to_value(&identity).unwrap();
+            // Json numbers can't hold the whole uid
+            assert_eq!(json["uid"], json!(format!("{:032x}", identity.uid)));
+            let decoded: Identity = serde_json::from_value(json).unwrap();
+            assert_eq!(decoded, identity);
+        }
+    }
+
+    #[test]
+    fn first_version_identity_decodes() {
+        // As stored before the optional fields were added
+        let identity: Identity = serde_json::from_value(json!({
+            "version": 1,
+            "uid": "000000000000000000000000000000ff",
+            "created_at": 1_700_000_000,
+        }))
+        .unwrap();
+        assert_eq!(identity.uid, 0xff);
+        assert_eq!(identity.display_name, None);
+        assert_eq!(identity.account, None);
+    }
+
+    #[tokio::test]
+    async fn newer_identity_versions_are_rejected() {
+        let session = session();
+        let mut identity = Identity::new(1);
+        identity.version = IDENTITY_VERSION + 1;
+        session.insert(IDENTITY_KEY, identity).await.unwrap();
+        assert!(get_identity(&session).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn legacy_uid_is_migrated_little_endian() {
+        let session = session();
+        let mut bytes = vec![0u8; 16];
+        bytes[0] = 1;
+        session.insert(LEGACY_UID_KEY, bytes).await.unwrap();
+
+        let identity = get_identity(&session).await.unwrap().unwrap();
+        assert_eq!(identity.uid, 1, "legacy uids are read as little-endian");
+        assert_eq!(identity.version, IDENTITY_VERSION);
+        assert_eq!(session.get::<Vec<u8>>(LEGACY_UID_KEY).await.unwrap(), None);
+        assert_eq!(
+            session.get::<Identity>(IDENTITY_KEY).await.unwrap(),
+            Some(identity.clone())
+        );
+        // Later loads see the migrated identity
+        assert_eq!(get_identity(&session).await.unwrap(), Some(identity));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_requests_get_the_same_identity() {
+        let dir = std::env::temp_dir().join(format!("scrum-poker-uid-{:x}", random::<u64>()));
+        let stores = [
+            AnySessionStore::Memory(MemorySessionStore::default()),
+            AnySessionStore::File(crate::session_store::FileSessionStore::new(&dir).await.unwrap()),
+        ];
+        for store in stores {
+            // A new session, the first request reserves its identity in the store
+            let first = Session::new(None, Arc::new(store.clone()), None);
+            let identity = get_or_create_identity(&first, &store).await.unwrap();
+            let session_id = first.id().expect("Session to be saved");
+            let reserved = store.create_identity(&session_id, Vec::new()).await.unwrap();
+            assert_eq!(serde_json::from_slice::<Identity>(&reserved).unwrap(), identity);
+
+            // A known session without an identity yet, requested all at once
+            let session = Session::new(None, Arc::new(store.clone()), None);
+            session.save().await.unwrap();
+            let session_id = session.id().unwrap();
+            let requests: Vec<_> = (0..8)
+                .map(|_| {
+                    let store = store.clone();
+                    tokio::spawn(async move {
+                        let session = Session::new(Some(session_id), Arc::new(store.clone()), None);
+                        get_or_create_identity(&session, &store).await.unwrap().uid
+                    })
+                })
+                .collect();
+            let mut uids = Vec::new();
+            for request in requests {
+                uids.push(request.await.unwrap());
+            }
+            assert!(uids.iter().all(|&uid| uid == uids[0]), "{uids:?}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn malformed_legacy_uid_is_rejected() {
+        let session = session();
+        session.insert(LEGACY_UID_KEY, vec![1u8; 15]).await.unwrap();
+        assert!(get_identity(&session).await.is_err());
+    }
 }